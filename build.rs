@@ -3,16 +3,323 @@ use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
 
+const CONFIG_FILE: &str = "riscv-sbi-rt.toml";
+
+// Keys of `riscv-sbi-rt.toml`; each could be overridden by environment
+// variable `RISCV_SBI_RT_<KEY>`, e.g. `RISCV_SBI_RT_HEAP_SIZE=1M`
+const KEYS: &[&str] = &[
+    "platform",
+    "base_address",
+    "ram_size",
+    "hart_stack_size",
+    "heap_size",
+    "frame_size",
+    "harts",
+];
+
+/// Memory layout of the supervisor
+#[derive(Debug)]
+struct Layout {
+    /// Address where the supervisor is linked and loaded to
+    base_address: u64,
+    /// Size of RAM from `base_address`
+    ram_size: u64,
+    hart_stack_size: u64,
+    heap_size: u64,
+    frame_size: u64,
+    harts: u64,
+}
+
+impl Layout {
+    fn preset(platform: &str, pointer_width: &str) -> Layout {
+        match platform {
+            // OpenSBI jumps to 0x80200000 (RV64) or 0x80400000 (RV32), 128M RAM by default
+            "qemu-virt" => {
+                let base_address = if pointer_width == "32" {
+                    0x8040_0000
+                } else {
+                    0x8020_0000
+                };
+                Layout {
+                    base_address,
+                    ram_size: 0x8800_0000 - base_address,
+                    hart_stack_size: 128 << 10,
+                    heap_size: 0,
+                    frame_size: 0,
+                    harts: 8,
+                }
+            }
+            // RustSBI jumps to 0x80020000; 6M general purpose SRAM
+            "k210" => Layout {
+                base_address: 0x8002_0000,
+                ram_size: 0x8060_0000 - 0x8002_0000,
+                hart_stack_size: 64 << 10,
+                heap_size: 0,
+                frame_size: 0,
+                harts: 2,
+            },
+            // OpenSBI jumps to 0x80200000; 16G DDR, hart 0 is the S7 monitor core without S-mode
+            "fu740" => Layout {
+                base_address: 0x8020_0000,
+                ram_size: (16 << 30) - (2 << 20),
+                hart_stack_size: 128 << 10,
+                heap_size: 0,
+                frame_size: 0,
+                harts: 5,
+            },
+            _ => panic!(
+                "riscv-sbi-rt: unknown platform `{}`, expected one of `qemu-virt`, `k210` or `fu740`",
+                platform
+            ),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        let value = parse_size(value).unwrap_or_else(|| {
+            panic!("riscv-sbi-rt: invalid value `{}` for `{}`", value, key)
+        });
+        match key {
+            "base_address" => self.base_address = value,
+            "ram_size" => self.ram_size = value,
+            "hart_stack_size" => self.hart_stack_size = value,
+            "heap_size" => self.heap_size = value,
+            "frame_size" => self.frame_size = value,
+            "harts" => self.harts = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn linker_script(&self) -> String {
+        assert!(self.harts > 0, "riscv-sbi-rt: `harts` must not be zero");
+        format!(
+            "/* Generated by riscv-sbi-rt build script */
+MEMORY {{
+    RAM : ORIGIN = {:#x}, LENGTH = {:#x}
+}}
+
+_hart_stack_size = {:#x};
+_max_hart_id = {};
+_heap_size = {:#x};
+_frame_size = {:#x};
+
+REGION_ALIAS(\"REGION_TEXT\", RAM);
+REGION_ALIAS(\"REGION_RODATA\", RAM);
+REGION_ALIAS(\"REGION_DATA\", RAM);
+REGION_ALIAS(\"REGION_BSS\", RAM);
+REGION_ALIAS(\"REGION_HEAP\", RAM);
+REGION_ALIAS(\"REGION_STACK\", RAM);
+REGION_ALIAS(\"REGION_FRAME\", RAM);
+
+",
+            self.base_address,
+            self.ram_size,
+            self.hart_stack_size,
+            self.harts - 1,
+            self.heap_size,
+            self.frame_size,
+        )
+    }
+}
+
 fn main() {
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let pointer_width = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap();
+
+    // Collect configuration; environment variables take precedence over the file
+    let mut config = Vec::new();
+    println!("cargo:rerun-if-env-changed=RISCV_SBI_RT_CONFIG");
+    if let Some(path) = config_file() {
+        println!("cargo:rerun-if-changed={}", path.display());
+        config.extend(parse_config(&fs::read_to_string(&path).unwrap()));
+    }
+    for key in KEYS {
+        let var = format!("RISCV_SBI_RT_{}", key.to_uppercase());
+        println!("cargo:rerun-if-env-changed={}", var);
+        if let Ok(value) = env::var(&var) {
+            config.push((key.to_string(), value));
+        }
+    }
 
-    // Put the linker script somewhere the linker can find it
-    fs::File::create(out_dir.join("sbi64.x"))
+    // Generate the memory layout if configured, otherwise users provide their own
+    let mut script = String::new();
+    if !config.is_empty() {
+        let platform = config
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "platform")
+            .map(|(_, value)| value.as_str())
+            .unwrap_or("qemu-virt");
+        let mut layout = Layout::preset(platform, &pointer_width);
+        for (key, value) in config.iter().filter(|(key, _)| key != "platform") {
+            layout.set(key, value);
+        }
+        script.push_str(&layout.linker_script());
+    }
+    script.push_str(include_str!("sbi.x"));
+
+    // Put the linker script somewhere the linker can find it,
+    // named `sbi32.x` or `sbi64.x` after the target XLEN
+    let script_name = format!("sbi{}.x", pointer_width);
+    fs::File::create(out_dir.join(script_name))
         .unwrap()
-        .write_all(include_bytes!("sbi64.x"))
+        .write_all(script.as_bytes())
         .unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
 
+    // Symbol table for backtraces, from `nm -n` output of a previous build
+    println!("cargo:rerun-if-env-changed=RISCV_SBI_RT_SYMBOLS");
+    let symbols = match env::var("RISCV_SBI_RT_SYMBOLS") {
+        Ok(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            symbol_table(&fs::read_to_string(&path).unwrap())
+        }
+        Err(_) => Vec::new(),
+    };
+    fs::write(out_dir.join("symbols.bin"), symbols).unwrap();
+    if env::var_os("CARGO_FEATURE_BACKTRACE").is_some() {
+        let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS")
+            .or_else(|_| env::var("RUSTFLAGS"))
+            .unwrap_or_default();
+        if !rustflags.contains("force-frame-pointers") {
+            println!(
+                "cargo:warning=riscv-sbi-rt: `backtrace` needs `-C force-frame-pointers=yes` in rustflags"
+            );
+        }
+    }
+
     println!("cargo:rerun-if-changed=build.rs");
-    println!("cargo:rerun-if-changed=sbi.ld");
+    println!("cargo:rerun-if-changed=sbi.x");
+}
+
+// Records of `address: u64, name length: u32, name` for text symbols, sorted by address
+fn symbol_table(nm: &str) -> Vec<u8> {
+    let mut symbols: Vec<(u64, String)> = nm
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+            match fields.next()? {
+                "T" | "t" | "W" | "w" => {}
+                _ => return None,
+            }
+            Some((address, demangle(fields.next()?)))
+        })
+        .collect();
+    symbols.sort_by_key(|&(address, _)| address);
+    let mut table = Vec::new();
+    for (address, name) in symbols {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        table.extend_from_slice(name.as_bytes());
+    }
+    table
+}
+
+// Demangle legacy Rust symbols like `_ZN4core9panicking5panic17h0123456789abcdefE`,
+// without the hash; other names are kept as they are
+fn demangle(name: &str) -> String {
+    let mut rest = match name.strip_prefix("_ZN").and_then(|n| n.strip_suffix('E')) {
+        Some(rest) => rest,
+        None => return name.to_string(),
+    };
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.chars().take_while(char::is_ascii_digit).count();
+        let len = match rest[..digits].parse::<usize>() {
+            Ok(len) if digits + len <= rest.len() => len,
+            _ => return name.to_string(),
+        };
+        let segment = &rest[digits..digits + len];
+        // An underscore is prepended to segments starting with an escape
+        if segment.starts_with("_$") {
+            segments.push(&segment[1..]);
+        } else {
+            segments.push(segment);
+        }
+        rest = &rest[digits + len..];
+    }
+    if let Some(hash) = segments.last() {
+        if hash.len() == 17 && hash.starts_with('h') {
+            segments.pop();
+        }
+    }
+    let mut demangled = segments.join("::");
+    for &(from, to) in &[
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$RF$", "&"),
+        ("$BP$", "*"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+        ("..", "::"),
+    ] {
+        demangled = demangled.replace(from, to);
+    }
+    demangled
+}
+
+// File named by `RISCV_SBI_RT_CONFIG`; build scripts of dependencies can't tell which crate
+// is being built, so the file is never searched for
+fn config_file() -> Option<PathBuf> {
+    let path = PathBuf::from(env::var_os("RISCV_SBI_RT_CONFIG")?);
+    if !path.is_absolute() {
+        panic!(
+            "riscv-sbi-rt: RISCV_SBI_RT_CONFIG must be an absolute path, got `{}`",
+            path.display()
+        );
+    }
+    if !path.is_file() {
+        panic!(
+            "riscv-sbi-rt: RISCV_SBI_RT_CONFIG points to `{}`, which doesn't exist",
+            path.display()
+        );
+    }
+    Some(path)
+}
+
+// Flat `key = value` lines; comments, blank lines and table headers are skipped
+fn parse_config(text: &str) -> Vec<(String, String)> {
+    let mut config = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() || line.starts_with('[') {
+            continue;
+        }
+        let mut kv = line.splitn(2, '=');
+        let key = kv.next().unwrap().trim();
+        let value = kv
+            .next()
+            .unwrap_or_else(|| panic!("riscv-sbi-rt: expected `key = value` in {}", CONFIG_FILE))
+            .trim()
+            .trim_matches('"');
+        if !KEYS.contains(&key) {
+            panic!("riscv-sbi-rt: unknown key `{}` in {}", key, CONFIG_FILE);
+        }
+        config.push((key.to_string(), value.to_string()));
+    }
+    config
+}
+
+// Integer in decimal or hex, with optional `K`, `M` or `G` suffix
+fn parse_size(value: &str) -> Option<u64> {
+    let value = value.replace('_', "");
+    let (digits, shift) = match value.chars().last()? {
+        'K' | 'k' => (&value[..value.len() - 1], 10),
+        'M' | 'm' => (&value[..value.len() - 1], 20),
+        'G' | 'g' => (&value[..value.len() - 1], 30),
+        _ => (&value[..], 0),
+    };
+    let number = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    number.checked_mul(1 << shift)
 }
@@ -0,0 +1,16 @@
+MEMORY {
+    /* RV32 runs without a boot page, at physical addresses */
+    DRAM : ORIGIN = 0x80400000, LENGTH = 124M
+}
+
+/* Start harts 0..=3 for the `-smp 4` smoke test */
+PROVIDE(_max_hart_id = 3);
+
+/* Map the runtime regions into memory areas */
+REGION_ALIAS("REGION_TEXT", DRAM);
+REGION_ALIAS("REGION_RODATA", DRAM);
+REGION_ALIAS("REGION_DATA", DRAM);
+REGION_ALIAS("REGION_BSS", DRAM);
+REGION_ALIAS("REGION_HEAP", DRAM);
+REGION_ALIAS("REGION_STACK", DRAM);
+REGION_ALIAS("REGION_FRAME", DRAM);
@@ -0,0 +1,16 @@
+MEMORY {
+    /* Position-independent builds run at physical addresses, wherever they're loaded */
+    DRAM : ORIGIN = 0x80200000, LENGTH = 126M
+}
+
+/* Start harts 0..=3 for the `-smp 4` smoke test */
+PROVIDE(_max_hart_id = 3);
+
+/* Map the runtime regions into memory areas */
+REGION_ALIAS("REGION_TEXT", DRAM);
+REGION_ALIAS("REGION_RODATA", DRAM);
+REGION_ALIAS("REGION_DATA", DRAM);
+REGION_ALIAS("REGION_BSS", DRAM);
+REGION_ALIAS("REGION_HEAP", DRAM);
+REGION_ALIAS("REGION_STACK", DRAM);
+REGION_ALIAS("REGION_FRAME", DRAM);
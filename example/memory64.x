@@ -0,0 +1,18 @@
+MEMORY {
+    /* Virtual address mapped memory area */
+    DRAM : ORIGIN = 0xffffffff80200000, LENGTH = 126M
+}
+
+/* Start harts 0..=3 for the `-smp 4` smoke test */
+PROVIDE(_max_hart_id = 3);
+/* Page tables for splitting the boot page around stack guard pages */
+PROVIDE(_frame_size = 16 * 4K);
+
+/* Map the runtime regions into memory areas */
+REGION_ALIAS("REGION_TEXT", DRAM);
+REGION_ALIAS("REGION_RODATA", DRAM);
+REGION_ALIAS("REGION_DATA", DRAM);
+REGION_ALIAS("REGION_BSS", DRAM);
+REGION_ALIAS("REGION_HEAP", DRAM);
+REGION_ALIAS("REGION_STACK", DRAM);
+REGION_ALIAS("REGION_FRAME", DRAM);
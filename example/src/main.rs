@@ -1,23 +1,145 @@
+//! Smoke test: boot all harts of `qemu-system-riscv64 -smp 4` in the higher half,
+//! then call a function on each secondary hart over IPI.
+//!
+//! On RV32, and with the `pie` feature, the harts run at physical addresses without
+//! a boot page. With the `kaslr` feature, they run at the random base chosen at boot.
+
 #![no_std]
 #![no_main]
+#![feature(llvm_asm, global_asm)]
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use riscv_sbi_rt::println;
+
+#[cfg(all(target_pointer_width = "64", not(feature = "pie")))]
+riscv_sbi_rt::boot_page_sv39! {
+    (0xffffffff_80000000 => 0x00000000_80000000, rwx);
+    // Devices, including the UART at 0x10000000
+    (0xffffffff_00000000 => 0x00000000_00000000, rw);
+    (0x00000000_80000000 => 0x00000000_80000000, rwx);
+}
+
+// Where the boot page maps devices; without paging they're at their physical addresses
+#[cfg(all(target_pointer_width = "64", not(feature = "pie")))]
+const DEVICE_OFFSET: usize = 0xffffffff_00000000;
+
+#[cfg(all(target_pointer_width = "64", not(feature = "pie")))]
+fn in_higher_half(addr: usize) -> bool {
+    addr >= 0xffffffff_80000000
+}
+
+// KASLR keeps the lower half identity mapped
+#[cfg(feature = "kaslr")]
+const DEVICE_OFFSET: usize = 0;
+
+// Anywhere in the Sv39 higher half
+#[cfg(feature = "kaslr")]
+fn in_higher_half(addr: usize) -> bool {
+    addr >= 0xffffffc0_00000000
+}
+
+#[cfg(all(any(target_pointer_width = "32", feature = "pie"), not(feature = "kaslr")))]
+fn in_higher_half(_addr: usize) -> bool {
+    true
+}
+
+static BOOTED: AtomicBool = AtomicBool::new(false);
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+// `_mp_hook` runs before .bss is zeroed, keep the flag in .data
+#[link_section = ".data"]
+static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+// OpenSBI picks the boot hart by lottery; whichever hart comes first initializes memory
+#[export_name = "_mp_hook"]
+pub extern "Rust" fn mp_hook(_hartid: usize, _dtb_pa: usize) -> bool {
+    !CLAIMED.swap(true, Ordering::AcqRel)
+}
+
+#[riscv_sbi_rt::entry]
+fn main(hartid: usize, dtb_pa: usize) {
+    // Fail the smoke test right away instead of hanging until timeout
+    riscv_sbi_rt::set_panic_policy(riscv_sbi_rt::PanicPolicy::Shutdown);
+    let sp = &hartid as *const _ as usize;
+    let gp: usize;
+    unsafe { llvm_asm!("mv $0, gp" : "=r"(gp)) };
+    assert!(in_higher_half(sp), "hart {} sp {:#x} not in higher half", hartid, sp);
+    assert!(in_higher_half(gp), "hart {} gp {:#x} not in higher half", hartid, gp);
+    assert!(in_higher_half(dtb_pa), "hart {} dtb {:#x} not in higher half", hartid, dtb_pa);
+
+    if BOOTED.swap(true, Ordering::AcqRel) {
+        println!("hart {} online, sp={:#x}", hartid, sp);
+        ONLINE.fetch_add(1, Ordering::AcqRel);
+        // Wait for messages from the boot hart
+        unsafe { riscv::register::sstatus::set_sie() };
+        loop {
+            unsafe { riscv::asm::wfi() };
+        }
+    }
+
+    // The runtime only sets up the UART by itself without paging
+    #[cfg(any(all(target_pointer_width = "64", not(feature = "pie")), feature = "kaslr"))]
+    {
+        let fdt = unsafe { riscv_sbi_rt::fdt::Fdt::from_ptr(dtb_pa) }.expect("no device tree");
+        riscv_sbi_rt::console::init_mapped(&fdt, DEVICE_OFFSET);
+    }
+    assert!(riscv_sbi_rt::console::uart().is_some(), "no 16550 UART console");
+    // Unmap guard pages below the hart stacks, splitting the gigapage of the boot page
+    // mapping them; secondary harts aren't started yet and see them at once
+    #[cfg(all(target_pointer_width = "64", not(feature = "pie")))]
+    {
+        use riscv_sbi_rt::paging::{BumpFrameAllocator, PageTable, Sv39};
+        let mut frames = BumpFrameAllocator::from_frame_region();
+        let mut page_table = unsafe { PageTable::<Sv39>::from_boot_page() };
+        page_table
+            .unmap_stack_guards(&mut frames)
+            .expect("failed to unmap stack guards");
+        riscv_sbi_rt::tlb::flush_local_all();
+        assert!(riscv_sbi_rt::stack::is_guarded());
+    }
+    println!("boot hart {}, dtb={:#x}, pong at {:#x}", hartid, dtb_pa, pong as usize);
+    #[cfg(feature = "kaslr")]
+    {
+        let offset = riscv_sbi_rt::va_pa_offset();
+        assert_ne!(offset, 0, "not randomized, no seed in the device tree");
+        println!("kaslr offset {:#x}", offset);
+    }
+    let dtb_phys = dtb_pa - riscv_sbi_rt::va_pa_offset();
+    let mut started = 0;
+    for id in (0..=riscv_sbi_rt::max_hart_id()).filter(|&id| id != hartid) {
+        let error = hart_start(id, riscv_sbi_rt::secondary_entry(), dtb_phys);
+        assert_eq!(error, 0, "failed to start hart {}: {}", id, error);
+        started += 1;
+    }
+    while ONLINE.load(Ordering::Acquire) < started {
+        core::sync::atomic::spin_loop_hint();
+    }
+    for id in (0..=riscv_sbi_rt::max_hart_id()).filter(|&id| id != hartid) {
+        riscv_sbi_rt::ipi::call_on_hart(id, pong, hartid).expect("failed to send IPI");
+    }
+    while PONGS.load(Ordering::Acquire) < started {
+        core::sync::atomic::spin_loop_hint();
+    }
+    println!("smp smoke test passed");
+}
+
+static PONGS: AtomicUsize = AtomicUsize::new(0);
+
+fn pong(from: usize) {
+    println!("hart {} called by hart {}", riscv_sbi_rt::hart::hart_id(), from);
+    PONGS.fetch_add(1, Ordering::AcqRel);
+}
 
-#[macro_use]
-extern crate log;
-#[macro_use]
-extern crate opensbi_rt;
-
-use log::LevelFilter;
-use opensbi_rt::sbi;
-
-#[no_mangle]
-extern "C" fn main(hartid: usize, dtb: usize) {
-    log::set_max_level(LevelFilter::Info);
-    println!("Hello, OpenSBI!");
-    println!("hartid={}, dtb={:#x}", hartid, dtb);
-    println!("spec_version = {:?}", sbi::base::get_spec_version());
-    println!("impl_id      = {:?}", sbi::base::get_impl_id());
-    println!("impl_version = {:?}", sbi::base::get_impl_version());
-    println!("mvendorid    = {:?}", sbi::base::get_mvendorid());
-    println!("marchid      = {:?}", sbi::base::get_marchid());
-    println!("mimpid       = {:?}", sbi::base::get_mimpid());
+// SBI HSM extension, `sbi_hart_start`
+fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> isize {
+    let error;
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}"(error)
+            : "{x10}"(hartid), "{x11}"(start_addr), "{x12}"(opaque),
+              "{x16}"(0), "{x17}"(0x48534D)
+            : "memory", "x11"
+            : "volatile");
+    }
+    error
 }
@@ -12,8 +12,8 @@ pub fn boot_page_content(entry_config: &EntryConfig, mode: Mode) -> TokenStream
                 .map(Literal::usize_unsuffixed);
             quote!( #( #pte , )* )
         }
-        // in Sv39 and Sv48, virtual page number contain 9 bits
-        Mode::Sv39 | Mode::Sv48 => {
+        // in Sv39, Sv48 and Sv57, virtual page number contain 9 bits
+        Mode::Sv39 | Mode::Sv48 | Mode::Sv57 => {
             let pte = (0..512)
                 .map(|idx| entry_config[idx])
                 .map(Literal::usize_unsuffixed);
@@ -22,6 +22,61 @@ pub fn boot_page_content(entry_config: &EntryConfig, mode: Mode) -> TokenStream
     }
 }
 
+/// Boot page trampoline shared by the boot hart and harts started by SBI HSM
+///
+/// Both `_start` and `_start_secondary` enable the boot page, relocate the
+/// device tree root by the offset from the physical to the virtual kernel
+/// image and jump to the virtual `_abs_start`, which then sets up `gp` and
+/// the per-hart `sp` with pc-relative loads, i.e. in the higher half.
+pub fn boot_page_trampoline(mode: Mode) -> String {
+    let (satp_mode, load, align, word) = match mode {
+        Mode::Sv32 => ("1 << 31", "lw", 2, ".word"),
+        Mode::Sv39 => ("8 << 60", "ld", 3, ".dword"),
+        Mode::Sv48 => ("9 << 60", "ld", 3, ".dword"),
+        Mode::Sv57 => ("10 << 60", "ld", 3, ".dword"),
+    };
+    format!(
+        "
+    .section .init.boot, \"ax\", @progbits
+    .globl _start
+    .globl _start_secondary
+_start:
+_start_secondary:
+    /* a0: hart id */
+    /* a1: device tree root, or `opaque` of HSM hart_start */
+    lla t1, _boot_page
+    srli t1, t1, 12
+    li t0, {satp_mode}
+    or t0, t0, t1
+
+    /* t2: physical _abs_start, t3: virtual _abs_start */
+    lla t2, _abs_start
+    .option push
+    .option norelax
+1:
+    auipc t3, %pcrel_hi(2f)
+    {load} t3, %pcrel_lo(1b)(t3)
+    .option pop
+
+    csrw satp, t0
+    sfence.vma
+
+    /* a2: offset from physical to virtual address of the kernel image */
+    sub a2, t3, t2
+    /* Relocate the device tree root into the higher half */
+    add a1, a1, a2
+    jr t3
+    .align {align}
+2:
+    {word} _abs_start
+",
+        satp_mode = satp_mode,
+        load = load,
+        align = align,
+        word = word,
+    )
+}
+
 /*
 
     Generated like:
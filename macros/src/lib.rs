@@ -12,8 +12,8 @@ use std::collections::HashSet;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 use syn::{
-    parse, spanned::Spanned, AttrStyle, Attribute, FnArg, Ident, Item, ItemFn, ItemStatic,
-    ReturnType, Stmt, Type, Visibility,
+    parse, spanned::Spanned, AttrStyle, Attribute, FnArg, Ident, Item, ItemFn, ItemStatic, Lit,
+    MetaNameValue, ReturnType, Stmt, Type, Visibility,
 };
 
 mod codegen;
@@ -132,9 +132,11 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
 /// - SupervisorTimer
 /// - UserExternal
 /// - SupervisorExternal
+/// - CounterOverflow (Sscofpmf)
+/// - Local16 to Local63 (AIA local interrupts; up to Local31 on RV32)
 ///
-/// If you want to handle other interrupts that's not implemented in the RISC-V standard,
-/// you should provide a `DefaultHandler`, instead of using this attribute. (todo)
+/// Enable them in `sie` with `riscv_sbi_rt::enable_interrupt`. Interrupt codes beyond these
+/// go to `DefaultHandler`.
 ///
 /// # Usage
 ///
@@ -145,7 +147,11 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
 /// function body.
 ///
 /// If this interrupt handler is not overriden by custom `#[interrupt]` functions, the runtime
-/// would trigger `DefaultHandler` instead.
+/// would trigger `DefaultHandler` instead, except for `SupervisorTimer` which runs the
+/// timers of `riscv_sbi_rt::timer`, `SupervisorSoft` which handles messages of
+/// `riscv_sbi_rt::ipi`, and `SupervisorExternal` which dispatches PLIC interrupts to
+/// `#[external_interrupt]` handlers. Call `handle_interrupt` of the module from a custom
+/// handler to keep them working.
 ///
 /// # Example
 ///
@@ -157,7 +163,7 @@ pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
 /// fn SupervisorTimer() {
 ///     static mut TICKS: usize = 0;
 ///
-///     sbi::legacy::set_timer(time::read64().wrapping_add(INTERVAL));
+///     riscv_sbi_rt::sbi::set_timer(time::read64().wrapping_add(INTERVAL));
 ///
 ///     *TICKS += 1;
 ///     if *TICKS % 100 == 0 {
@@ -373,6 +379,205 @@ pub fn pre_init(args: TokenStream, input: TokenStream) -> TokenStream {
     .into()
 }
 
+/// Attribute to declare a function called on panic.
+///
+/// The function must have the signature of `fn(&PanicInfo)`.
+///
+/// It's called on the panicking hart after the panic report is printed, and before other
+/// harts are stopped and the panic policy is applied. Only the first panic calls it.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[panic_hook]
+/// fn on_panic(info: &PanicInfo) {
+///     flush_logs();
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn panic_hook(args: TokenStream, input: TokenStream) -> TokenStream {
+    let f: ItemFn = syn::parse(input).expect("`#[panic_hook]` must be applied to a function");
+
+    // check the function signature
+    let valid_signature = f.sig.constness.is_none()
+        && f.vis == Visibility::Inherited
+        && f.sig.unsafety.is_none()
+        && f.sig.abi.is_none()
+        && f.sig.inputs.len() == 1
+        && f.sig.generics.params.is_empty()
+        && f.sig.generics.where_clause.is_none()
+        && f.sig.variadic.is_none()
+        && match f.sig.output {
+            ReturnType::Default => true,
+            ReturnType::Type(_, ref ty) => match **ty {
+                Type::Tuple(ref tuple) => tuple.elems.is_empty(),
+                _ => false,
+            },
+        };
+
+    if !valid_signature {
+        return parse::Error::new(
+            f.span(),
+            "`#[panic_hook]` function must have signature `fn(&PanicInfo)`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    let attrs = f.attrs;
+    let ident = f.sig.ident;
+    let inputs = f.sig.inputs;
+    let block = f.block;
+
+    quote!(
+        #[export_name = "__panic_hook"]
+        #(#attrs)*
+        pub fn #ident(#inputs) #block
+    )
+    .into()
+}
+
+/// Attribute to declare a handler of a PLIC interrupt source
+///
+/// The default `SupervisorExternal` handler calls the function when `irq` is claimed on
+/// any hart, then completes it. The function must have the signature `fn()`.
+/// Enable the source with `riscv_sbi_rt::plic`, or `riscv_sbi_rt::aia` where `irq` is
+/// an APLIC source.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[external_interrupt(irq = 10)]
+/// fn uart() {
+///     println!("UART interrupt");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn external_interrupt(args: TokenStream, input: TokenStream) -> TokenStream {
+    let f: ItemFn =
+        syn::parse(input).expect("`#[external_interrupt]` must be applied to a function");
+
+    let valid_signature = f.sig.constness.is_none()
+        && f.vis == Visibility::Inherited
+        && f.sig.unsafety.is_none()
+        && f.sig.abi.is_none()
+        && f.sig.inputs.is_empty()
+        && f.sig.generics.params.is_empty()
+        && f.sig.generics.where_clause.is_none()
+        && f.sig.variadic.is_none()
+        && match f.sig.output {
+            ReturnType::Default => true,
+            ReturnType::Type(_, ref ty) => match **ty {
+                Type::Tuple(ref tuple) => tuple.elems.is_empty(),
+                _ => false,
+            },
+        };
+
+    if !valid_signature {
+        return parse::Error::new(
+            f.span(),
+            "`#[external_interrupt]` handlers must have signature `fn()`",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let irq = match syn::parse::<MetaNameValue>(args) {
+        Ok(MetaNameValue {
+            ref path,
+            lit: Lit::Int(ref irq),
+            ..
+        }) if path.is_ident("irq") => match irq.base10_parse::<usize>() {
+            Ok(irq) if irq != 0 => irq,
+            _ => {
+                return parse::Error::new(irq.span(), "IRQ must be a positive integer")
+                    .to_compile_error()
+                    .into()
+            }
+        },
+        _ => {
+            return parse::Error::new(
+                Span::call_site(),
+                "This attribute requires an argument like `irq = 10`",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+
+    let ident = &f.sig.ident;
+    let entry = Ident::new(
+        &format!("__RISCV_SBI_RT_EXTERNAL_INTERRUPT_{}", irq),
+        Span::call_site(),
+    );
+
+    quote!(
+        #f
+
+        #[doc(hidden)]
+        #[link_section = ".external_interrupts"]
+        #[used]
+        static #entry: riscv_sbi_rt::plic::ExternalInterrupt =
+            riscv_sbi_rt::plic::ExternalInterrupt {
+                irq: #irq,
+                handler: #ident,
+            };
+    )
+    .into()
+}
+
+/// Attribute to make a static variable hart-local.
+///
+/// `static NAME: T = expr;` becomes a `HartLocal<T>` in the `.hart_local` section;
+/// every hart gets its own copy initialized to `expr`. Access it with `NAME.with(|v| ...)`.
+///
+/// # Examples
+///
+/// ```ignore
+/// #[hart_local]
+/// static TICKS: Cell<usize> = Cell::new(0);
+///
+/// TICKS.with(|ticks| ticks.set(ticks.get() + 1));
+/// ```
+#[proc_macro_attribute]
+pub fn hart_local(args: TokenStream, input: TokenStream) -> TokenStream {
+    let var: ItemStatic = syn::parse(input).expect("`#[hart_local]` must be applied to a static");
+
+    if var.mutability.is_some() {
+        return parse::Error::new(
+            var.span(),
+            "`#[hart_local]` static must not be `mut`; use `Cell` or `RefCell` instead",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    if !args.is_empty() {
+        return parse::Error::new(Span::call_site(), "This attribute accepts no arguments")
+            .to_compile_error()
+            .into();
+    }
+
+    let attrs = var.attrs;
+    let vis = var.vis;
+    let ident = var.ident;
+    let ty = var.ty;
+    let expr = var.expr;
+
+    quote!(
+        #(#attrs)*
+        #[link_section = ".hart_local"]
+        #vis static #ident: riscv_sbi_rt::HartLocal<#ty> = riscv_sbi_rt::HartLocal::new(#expr);
+    )
+    .into()
+}
+
 // Extracts `static mut` vars from the beginning of the given statements
 fn extract_static_muts(
     stmts: impl IntoIterator<Item = Stmt>,
@@ -433,6 +638,12 @@ fn eq(attr: &Attribute, name: &str) -> bool {
 
 /// Init an Sv39 boot page before entering real start address.
 ///
+/// The generated trampoline is used by both `_start` (boot hart) and `_start_secondary`
+/// (harts started by SBI HSM, see `riscv_sbi_rt::secondary_entry`). It enables the boot page,
+/// then enters `_abs_start` at its virtual address, so `sp` and `gp` are set up in the
+/// higher half. `dtb_pa` is relocated by the same offset as the kernel image, thus the device
+/// tree must lie in a region mapped alongside the kernel.
+///
 /// ```no_run
 /// #[cfg(target_pointer_width = "64")]
 /// riscv_sbi_rt::boot_page_sv39! {
@@ -449,36 +660,15 @@ pub fn boot_page_sv39(item: TokenStream) -> TokenStream {
     };
 
     let boot_page_content = codegen::boot_page_content(&entry_config, Mode::Sv39);
+    let trampoline = codegen::boot_page_trampoline(Mode::Sv39);
 
     quote!(
         #[repr(align(4096))]
         #[repr(C)]
         struct __BootPage([usize; 512]);
         #[export_name = "_boot_page"]
-        static __BOOT_PAGE: __BootPage = __BootPage([ #boot_page_content ]);
-        extern { fn _abs_start() -> !; }
-        global_asm!("
-    .section .init
-    .globl _start
-_start: 
-    la t1, _boot_page
-    srli t1, t1, 12
-    li t0, 8 << 60
-    or t0, t0, t1
-    csrw satp, t0
-    sfence.vma
-    
-    .option push
-    .option norelax
-1:
-    auipc ra, %pcrel_hi(1f)
-    ld ra, %pcrel_lo(1b)(ra)
-    jr ra
-    .align  3
-1:
-    .dword _abs_start
-.option pop
-        ");
+        static mut __BOOT_PAGE: __BootPage = __BootPage([ #boot_page_content ]);
+        global_asm!(#trampoline);
     )
     .into()
 }
@@ -492,41 +682,42 @@ pub fn boot_page_sv48(item: TokenStream) -> TokenStream {
     };
 
     let boot_page_content = codegen::boot_page_content(&entry_config, Mode::Sv48);
+    let trampoline = codegen::boot_page_trampoline(Mode::Sv48);
+
+    quote!(
+        #[repr(align(4096))]
+        #[repr(C)]
+        struct __BootPage([usize; 512]);
+        #[export_name = "_boot_page"]
+        static mut __BOOT_PAGE: __BootPage = __BootPage([ #boot_page_content ]);
+        global_asm!(#trampoline);
+    )
+    .into()
+}
+
+/// Init an Sv57 boot page before entering real start address.
+#[proc_macro]
+pub fn boot_page_sv57(item: TokenStream) -> TokenStream {
+    let entry_config = match syntax::parse(item.into(), Mode::Sv57) {
+        Err(e) => return e.to_compile_error().into(),
+        Ok(x) => x,
+    };
+
+    let boot_page_content = codegen::boot_page_content(&entry_config, Mode::Sv57);
+    let trampoline = codegen::boot_page_trampoline(Mode::Sv57);
 
     quote!(
         #[repr(align(4096))]
         #[repr(C)]
         struct __BootPage([usize; 512]);
         #[export_name = "_boot_page"]
-        static __BOOT_PAGE: __BootPage = __BootPage([ #boot_page_content ]);
-        extern { fn _abs_start() -> !; }
-        global_asm!("
-    .section .init
-    .globl _start
-_start: 
-    la t1, _boot_page
-    srli t1, t1, 12
-    li t0, 9 << 60
-    or t0, t0, t1
-    csrw satp, t0
-    sfence.vma
-    
-    .option push
-    .option norelax
-1:
-    auipc ra, %pcrel_hi(1f)
-    ld ra, %pcrel_lo(1b)(ra)
-    jr ra
-    .align  3
-1:
-    .dword _abs_start
-.option pop
-        ");
+        static mut __BOOT_PAGE: __BootPage = __BootPage([ #boot_page_content ]);
+        global_asm!(#trampoline);
     )
     .into()
 }
 
-// There should be sv57, sv64 here in the future
+// There should be sv64 here in the future
 
 /// Init an Sv32 boot page before entering real start address.
 ///
@@ -546,36 +737,15 @@ pub fn boot_page_sv32(item: TokenStream) -> TokenStream {
     };
 
     let boot_page_content = codegen::boot_page_content(&entry_config, Mode::Sv32);
+    let trampoline = codegen::boot_page_trampoline(Mode::Sv32);
 
     quote!(
         #[repr(align(4096))]
         #[repr(C)]
         struct __BootPage([usize; 1024]);
         #[export_name = "_boot_page"]
-        static __BOOT_PAGE: __BootPage = __BootPage([ #boot_page_content ]);
-        extern { fn _abs_start() -> !; }
-        global_asm!("
-    .section .init
-    .globl _start
-_start: 
-    la t1, _boot_page
-    srli t1, t1, 12
-    li t0, 1 << 31
-    or t0, t0, t1
-    csrw satp, t0
-    sfence.vma
-    
-    .option push
-    .option norelax
-1:
-    auipc ra, %pcrel_hi(1f)
-    lw ra, %pcrel_lo(1b)(ra)
-    jr ra
-    .align  2
-1:
-    .word _abs_start
-.option pop
-        ");
+        static mut __BOOT_PAGE: __BootPage = __BootPage([ #boot_page_content ]);
+        global_asm!(#trampoline);
     )
     .into()
 }
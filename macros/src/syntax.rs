@@ -11,6 +11,7 @@ pub enum Mode {
     Sv32,
     Sv39,
     Sv48,
+    Sv57,
 }
 
 #[derive(Debug)]
@@ -22,7 +23,7 @@ impl EntryConfig {
     pub fn new(mode: Mode) -> Self {
         let cap = match mode {
             Mode::Sv32 => 1024,
-            Mode::Sv39 | Mode::Sv48 => 512,
+            Mode::Sv39 | Mode::Sv48 | Mode::Sv57 => 512,
         };
         EntryConfig { pte: vec![0; cap] }
     }
@@ -198,11 +199,26 @@ fn parse_virt_page_number_2(literal: Literal, mode: Mode) -> Result<usize> {
                 ));
             }
         }
+        Mode::Sv57 => {
+            if !is_lower_bits_zero(vaddr, 48) {
+                return Err(Error::new(
+                    span,
+                    "expected Sv57 virtual address with bits 0..=47 zeroed",
+                ));
+            }
+            if !is_sign_extend_ok(vaddr, 56) {
+                return Err(Error::new(
+                    span,
+                    "expected Sv57 sign extended virtual address i.e. bit 57..=64 should be all equal to bit 56"
+                ));
+            }
+        }
     }
     let pte_index = match mode {
         Mode::Sv32 => (vaddr >> 22) & 0x3FF, // 1024 entries
         Mode::Sv39 => (vaddr >> 30) & 0x1FF, // 512 entries
         Mode::Sv48 => (vaddr >> 39) & 0x1FF, // 512 entries
+        Mode::Sv57 => (vaddr >> 48) & 0x1FF, // 512 entries
     };
     Ok(pte_index)
 }
@@ -246,6 +262,14 @@ fn parse_phys_page_numbers(literal: Literal, mode: Mode) -> Result<usize> {
                 ));
             }
         }
+        Mode::Sv57 => {
+            if !has_at_most_cnt_bits_u128(paddr, 56) {
+                return Err(Error::new(
+                    span,
+                    "expected Sv57 physical address; only bits 0..56 are valid",
+                ));
+            }
+        }
     }
     let ppn = usize::try_from(paddr >> 2).expect("bug!");
     Ok(ppn)
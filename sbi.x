@@ -0,0 +1,281 @@
+/* Ref: riscv-rt/link.x */
+
+/* XLEN-agnostic; installed as `sbi32.x` or `sbi64.x` by the build script */
+
+PROVIDE(DefaultHandler = DefaultInterruptHandler);
+PROVIDE(ExceptionHandler = DefaultExceptionHandler);
+
+PROVIDE(UserSoft = DefaultHandler);
+PROVIDE(SupervisorSoft = DefaultSupervisorSoft);
+PROVIDE(UserTimer = DefaultHandler);
+PROVIDE(SupervisorTimer = DefaultSupervisorTimer);
+PROVIDE(UserExternal = DefaultHandler);
+PROVIDE(SupervisorExternal = DefaultSupervisorExternal);
+PROVIDE(CounterOverflow = DefaultHandler);
+/* AIA local interrupts; 32 to 63 on RV64 only */
+PROVIDE(Local16 = DefaultHandler);
+PROVIDE(Local17 = DefaultHandler);
+PROVIDE(Local18 = DefaultHandler);
+PROVIDE(Local19 = DefaultHandler);
+PROVIDE(Local20 = DefaultHandler);
+PROVIDE(Local21 = DefaultHandler);
+PROVIDE(Local22 = DefaultHandler);
+PROVIDE(Local23 = DefaultHandler);
+PROVIDE(Local24 = DefaultHandler);
+PROVIDE(Local25 = DefaultHandler);
+PROVIDE(Local26 = DefaultHandler);
+PROVIDE(Local27 = DefaultHandler);
+PROVIDE(Local28 = DefaultHandler);
+PROVIDE(Local29 = DefaultHandler);
+PROVIDE(Local30 = DefaultHandler);
+PROVIDE(Local31 = DefaultHandler);
+PROVIDE(Local32 = DefaultHandler);
+PROVIDE(Local33 = DefaultHandler);
+PROVIDE(Local34 = DefaultHandler);
+PROVIDE(Local35 = DefaultHandler);
+PROVIDE(Local36 = DefaultHandler);
+PROVIDE(Local37 = DefaultHandler);
+PROVIDE(Local38 = DefaultHandler);
+PROVIDE(Local39 = DefaultHandler);
+PROVIDE(Local40 = DefaultHandler);
+PROVIDE(Local41 = DefaultHandler);
+PROVIDE(Local42 = DefaultHandler);
+PROVIDE(Local43 = DefaultHandler);
+PROVIDE(Local44 = DefaultHandler);
+PROVIDE(Local45 = DefaultHandler);
+PROVIDE(Local46 = DefaultHandler);
+PROVIDE(Local47 = DefaultHandler);
+PROVIDE(Local48 = DefaultHandler);
+PROVIDE(Local49 = DefaultHandler);
+PROVIDE(Local50 = DefaultHandler);
+PROVIDE(Local51 = DefaultHandler);
+PROVIDE(Local52 = DefaultHandler);
+PROVIDE(Local53 = DefaultHandler);
+PROVIDE(Local54 = DefaultHandler);
+PROVIDE(Local55 = DefaultHandler);
+PROVIDE(Local56 = DefaultHandler);
+PROVIDE(Local57 = DefaultHandler);
+PROVIDE(Local58 = DefaultHandler);
+PROVIDE(Local59 = DefaultHandler);
+PROVIDE(Local60 = DefaultHandler);
+PROVIDE(Local61 = DefaultHandler);
+PROVIDE(Local62 = DefaultHandler);
+PROVIDE(Local63 = DefaultHandler);
+
+PROVIDE(__pre_init = default_pre_init);
+PROVIDE(__panic_hook = default_panic_hook);
+PROVIDE(_mp_hook = default_mp_hook);
+
+/* Provide supervisor runtime heap size; must be times of 4K */
+PROVIDE(_heap_size = 0);
+/* Provide supervisor stack size for each hart; must be times of 4K */
+PROVIDE(_hart_stack_size = 128K);
+/* Provide max hart id; harts with bigger id are halted at boot */
+PROVIDE(_max_hart_id = 0);
+/* Provide emergency trap stack size for each hart; must be times of 4K */
+PROVIDE(_trap_stack_size = 16K);
+/* Stack size for all harts */
+_stack_size = _hart_stack_size * (_max_hart_id + 1);
+/* Provide frame region size for page tables; must be times of 4K */
+PROVIDE(_frame_size = 0);
+/* Allow supervisor to redefine entry point address according to device */
+PROVIDE(_stext = ORIGIN(REGION_TEXT));
+
+/* Size of TLS blocks, and of hart blocks with the 64-byte hart area before */
+_tls_size = ALIGN(_etbss - _stdata, 16);
+_hart_block_size = ALIGN(64 + _tls_size, 64);
+
+/* 目标架构 */
+OUTPUT_ARCH(riscv)
+
+/* 执行入口 */
+ENTRY(_start)
+
+SECTIONS
+{
+    /* .text 字段 */
+    .text _stext : {
+        /* Loaded address of `_stext`, for self-relocation in `pie` feature */
+        __executable_start = .;
+        /* Place init sections first; boot page trampoline goes before all */
+        KEEP(*(.init.boot));
+        KEEP(*(.init));
+        KEEP(*(.init.rust));
+        /* 要链接的文件的 .text 字段集中放在这里 */
+        *(.text .text.*)
+        _etext = .;
+    } > REGION_TEXT
+
+    /* .rodata 字段 */
+    .rodata : ALIGN(4K) {
+        _srodata = .;
+        /* 要链接的文件的 .rodata 字段集中放在这里 */
+        *(.srodata .srodata.*);
+        *(.rodata .rodata.*)
+        /* handlers of `#[external_interrupt]`, looked up by IRQ */
+        . = ALIGN(8);
+        _sexternal_interrupts = .;
+        KEEP(*(.external_interrupts .external_interrupts.*))
+        _eexternal_interrupts = .;
+        /* 4-byte align the end (VMA) of this section.
+        This is required by LLD to ensure the LMA of the following .data
+        section will have the correct alignment. */
+        . = ALIGN(4);
+        _erodata = .;
+    } > REGION_RODATA
+
+    /* Dynamic relocations of a position-independent supervisor; */
+    /* R_RISCV_RELATIVE ones are applied at boot in `pie` feature */
+    .rela.dyn : ALIGN(8) {
+        _srela_dyn = .;
+        *(.rela.dyn .rela.*)
+        _erela_dyn = .;
+    } > REGION_RODATA
+
+    /* thread-local templates: initial values, then zeroed ones */
+    /* each hart's TLS block is initialized from them at boot */
+    .tdata : ALIGN(16) {
+        _stdata = .;
+        *(.tdata .tdata.*)
+        _etdata = .;
+    } > REGION_RODATA
+
+    .tbss (NOLOAD) : ALIGN(16) {
+        _stbss = .;
+        *(.tbss .tbss.*)
+        _etbss = .;
+    } > REGION_RODATA
+
+    /* .data 字段 */
+    .data : ALIGN(4K) { 
+        _sidata = LOADADDR(.data);
+        _sdata = .;
+        /* Must be called __global_pointer$ for linker relaxations to work. */
+        PROVIDE(__global_pointer$ = . + 0x800);
+        /* 要链接的文件的 .data 字段集中放在这里 */
+        *(.sdata .sdata.* .sdata2 .sdata2.*);
+        *(.data .data.*)
+        /* initial values of hart-local variables, copied for each hart at boot */
+        . = ALIGN(16);
+        _shart_local = .;
+        KEEP(*(.hart_local .hart_local.*))
+        . = ALIGN(16);
+        _ehart_local = .;
+        _edata = .;
+    } > REGION_DATA
+    
+
+    /* .bss 字段 */
+    .bss (NOLOAD) : ALIGN(4K) {
+        _sbss = .;
+        /* 要链接的文件的 .bss 字段集中放在这里 */
+        *(.sbss .bss .bss.*)
+        . = ALIGN(4);
+        _ebss = .;
+    } > REGION_BSS
+
+    /* hart-local blocks, one `.hart_local` copy for each hart */
+    .hart_local_blocks (NOLOAD) : ALIGN(16) {
+        _shart_locals = .;
+        . += (_ehart_local - _shart_local) * (_max_hart_id + 1);
+        _ehart_locals = .;
+    } > REGION_BSS
+
+    /* hart blocks, each a 64-byte hart area followed by a TLS block */
+    .hart_blocks (NOLOAD) : ALIGN(64) {
+        _shart_blocks = .;
+        . += _hart_block_size * (_max_hart_id + 1);
+        _ehart_blocks = .;
+    } > REGION_BSS
+
+    /* fictitious region that represents the memory available for the heap */
+    .heap (NOLOAD) : ALIGN(4K) {
+        _sheap = .;
+        . += _heap_size;
+        . = ALIGN(4);
+        _eheap = .;
+    } > REGION_HEAP
+
+    /* hart stacks; NOLOAD rather than INFO, so `_estack` and `_sstack` get addresses */
+    /* hart N uses the N-th `_hart_stack_size` slot from `_sstack` downwards */
+    .stack (NOLOAD) : ALIGN(4K) {
+        _estack = .;
+        . += _stack_size;
+        . = ALIGN(4);
+        _sstack = .;
+    } > REGION_STACK
+
+    /* emergency stacks for traps on an overflowed stack, laid out like .stack */
+    /* right above the hart stacks, so they don't move with other sections */
+    .trap_stack _sstack (NOLOAD) : {
+        _etrap_stack = .;
+        . += _trap_stack_size * (_max_hart_id + 1);
+        _strap_stack = .;
+    } > REGION_STACK
+
+    /* physical frames for page tables and other allocations */
+    .frame (NOLOAD) : ALIGN(4K) {
+        _sframe = .;
+        . += _frame_size;
+        _eframe = .;
+    } > REGION_FRAME
+
+    .eh_frame (INFO) : { KEEP(*(.eh_frame)) }
+    .eh_frame_hdr (INFO) : { *(.eh_frame_hdr) }
+}
+
+ASSERT(ORIGIN(REGION_TEXT) % 4K == 0, "
+ERROR(riscv-sbi-rt): the start of the REGION_TEXT must be 4K-byte aligned");
+
+ASSERT(ORIGIN(REGION_RODATA) % 4K == 0, "
+ERROR(riscv-sbi-rt): the start of the REGION_RODATA must be 4K-byte aligned");
+
+ASSERT(ORIGIN(REGION_DATA) % 4K == 0, "
+ERROR(riscv-sbi-rt): the start of the REGION_DATA must be 4K-byte aligned");
+
+ASSERT(ORIGIN(REGION_HEAP) % 4K == 0, "
+ERROR(riscv-sbi-rt): the start of the REGION_HEAP must be 4K-byte aligned");
+
+ASSERT(ORIGIN(REGION_STACK) % 4K == 0, "
+ERROR(riscv-sbi-rt): the start of the REGION_STACK must be 4K-byte aligned");
+
+ASSERT(ORIGIN(REGION_FRAME) % 4K == 0, "
+ERROR(riscv-sbi-rt): the start of the REGION_FRAME must be 4K-byte aligned");
+
+ASSERT(_frame_size % 4K == 0, "
+ERROR(riscv-sbi-rt): `_frame_size` must be times of 4K");
+
+ASSERT(_hart_stack_size % 4K == 0, "
+ERROR(riscv-sbi-rt): `_hart_stack_size` must be times of 4K");
+
+ASSERT(_trap_stack_size % 4K == 0 && _trap_stack_size > 0, "
+ERROR(riscv-sbi-rt): `_trap_stack_size` must be times of 4K");
+
+ASSERT(_sstack % 4K == 0 && _strap_stack % 4K == 0, "
+BUG(riscv-sbi-rt): hart stacks or trap stacks are not 4K-byte aligned");
+
+ASSERT(_sframe == _eframe || _sframe >= _strap_stack || _eframe <= _estack, "
+ERROR(riscv-sbi-rt): .frame overlaps the hart stacks or trap stacks");
+
+ASSERT(ALIGNOF(.tdata) <= 16 && ALIGNOF(.tbss) <= 16, "
+ERROR(riscv-sbi-rt): thread-local variables must not be aligned to more than 16 bytes");
+
+ASSERT(_stext % 4 == 0, "
+ERROR(riscv-sbi-rt): `_stext` must be 4-byte aligned");
+
+ASSERT(_sdata % 4 == 0 && _edata % 4 == 0, "
+BUG(riscv-sbi-rt): .data is not 4-byte aligned");
+
+ASSERT(_sidata % 4 == 0, "
+BUG(riscv-sbi-rt): the LMA of .data is not 4-byte aligned");
+
+ASSERT(_sbss % 4 == 0 && _ebss % 4 == 0, "
+BUG(riscv-sbi-rt): .bss is not 4-byte aligned");
+
+ASSERT(_sheap % 4 == 0, "
+BUG(riscv-sbi-rt): start of .heap is not 4-byte aligned");
+
+ASSERT(_stext + SIZEOF(.text) < ORIGIN(REGION_TEXT) + LENGTH(REGION_TEXT), "
+ERROR(riscv-sbi-rt): The .text section must be placed inside the REGION_TEXT region.
+Set _stext to an address smaller than 'ORIGIN(REGION_TEXT) + LENGTH(REGION_TEXT)'");
+
@@ -0,0 +1,288 @@
+//! Advanced interrupt architecture: IMSIC and APLIC in MSI mode
+//!
+//! [`init`] finds the supervisor IMSIC (`riscv,imsics`) and the APLIC domain sending MSIs
+//! to it in the device tree. Wired interrupts of APLIC source `N` are sent to a hart as
+//! interrupt identity `N` of its IMSIC interrupt file, so the same
+//! [`external_interrupt`](crate::external_interrupt) handlers as with the PLIC are used.
+//!
+//! ```ignore
+//! if aia::init(&fdt) {
+//!     aia::set_trigger(10, Trigger::LevelHigh)?;
+//!     aia::enable(10)?;
+//! } else {
+//!     plic::init(&fdt);
+//!     ...
+//! }
+//! ```
+//!
+//! The interrupt file of each hart is set up by its `stopei`, `siselect` and `sireg` CSRs,
+//! so every hart other than the one calling [`init`] must call [`init_hart`]. With paging,
+//! map the APLIC and call [`set_aplic_base`] with the mapped address.
+
+use crate::fdt::{Fdt, Node};
+use crate::hart::{self, HartLocal};
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Indirect registers of the interrupt file, through `siselect`
+const EIDELIVERY: usize = 0x70;
+const EITHRESHOLD: usize = 0x72;
+const EIE0: usize = 0xc0;
+
+// APLIC registers
+const DOMAINCFG: usize = 0;
+const SOURCECFG: usize = 0x4;
+const SETIENUM: usize = 0x1edc;
+const CLRIENUM: usize = 0x1fdc;
+const TARGET: usize = 0x3004;
+
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM_MSI: u32 = 1 << 2;
+const SOURCECFG_DELEGATE: u32 = 1 << 10;
+const TARGET_HART_SHIFT: u32 = 18;
+
+// Supervisor external interrupt of the hart-local interrupt controller
+const IRQ_S_EXT: u32 = 9;
+const NO_HART_INDEX: usize = usize::MAX;
+
+const XLEN: usize = core::mem::size_of::<usize>() * 8;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static APLIC_BASE: AtomicUsize = AtomicUsize::new(0);
+// Largest APLIC source, and largest interrupt identity of interrupt files
+static SOURCES: AtomicUsize = AtomicUsize::new(0);
+static IDENTITIES: AtomicUsize = AtomicUsize::new(0);
+
+// Index of the hart in the IMSIC, used as its hart index in APLIC targets
+#[link_section = ".hart_local"]
+static HART_INDEX: HartLocal<AtomicUsize> = HartLocal::new(AtomicUsize::new(NO_HART_INDEX));
+
+/// Error of AIA operations
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AiaError {
+    /// Source is 0, above `riscv,num-sources` of the APLIC, or above `riscv,num-ids`
+    /// of the IMSIC so it can't be delivered
+    InvalidIrq(usize),
+    /// Hart has no IMSIC interrupt file, or its id is above `max_hart_id`
+    NoInterruptFile(usize),
+}
+
+/// Trigger of an APLIC source
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trigger {
+    /// Rising edge
+    EdgeRising = 4,
+    /// Falling edge
+    EdgeFalling = 5,
+    /// High level
+    LevelHigh = 6,
+    /// Low level
+    LevelLow = 7,
+}
+
+/// Find the supervisor IMSIC and its APLIC domain, and set up the interrupt file of
+/// current hart
+///
+/// Returns false if there's no supervisor IMSIC, e.g. on platforms with a PLIC.
+/// Without an APLIC in MSI mode, only MSIs of devices arrive.
+pub fn init(fdt: &Fdt) -> bool {
+    let imsic = match fdt
+        .nodes()
+        .filter(|node| node.is_compatible("riscv,imsics"))
+        .find(|node| first_irq(node) == Some(IRQ_S_EXT))
+    {
+        Some(imsic) => imsic,
+        None => return false,
+    };
+    let identities = imsic.property_u32("riscv,num-ids").unwrap_or(63) as usize;
+    IDENTITIES.store(identities, Ordering::Relaxed);
+
+    // Hart index i is the i-th `<phandle irq>` pair, of the interrupt controller of a cpu
+    let harts = imsic.property("interrupts-extended").unwrap_or(&[]);
+    for (index, pair) in harts.chunks_exact(8).enumerate() {
+        let phandle = u32::from_be_bytes(pair[..4].try_into().unwrap());
+        match fdt.hart_of_controller(phandle) {
+            Some(hartid) if hartid <= crate::max_hart_id() => {
+                unsafe { &*HART_INDEX.as_ptr_of(hartid) }.store(index, Ordering::Relaxed)
+            }
+            _ => {}
+        }
+    }
+
+    let imsic_phandle = imsic.property_u32("phandle");
+    let aplic = fdt.nodes().find(|node| {
+        node.is_compatible("riscv,aplic")
+            && imsic_phandle.is_some()
+            && node.property_u32("msi-parent") == imsic_phandle
+    });
+    if let Some((base, _)) = aplic.and_then(|aplic| aplic.reg().next()) {
+        let sources = aplic.and_then(|aplic| aplic.property_u32("riscv,num-sources"));
+        APLIC_BASE.store(base as usize, Ordering::Relaxed);
+        SOURCES.store(sources.unwrap_or(0) as usize, Ordering::Relaxed);
+        write(DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM_MSI);
+    }
+
+    ACTIVE.store(true, Ordering::Release);
+    init_hart();
+    true
+}
+
+// Interrupt of the first `<phandle irq>` pair in `interrupts-extended`
+fn first_irq(node: &Node) -> Option<u32> {
+    let value = node.property("interrupts-extended")?;
+    Some(u32::from_be_bytes(value.get(4..8)?.try_into().ok()?))
+}
+
+/// Set up the interrupt file of current hart, after [`init`] on any hart
+///
+/// Enables delivery and all interrupt identities; which hart gets a source is chosen
+/// by the APLIC target.
+pub fn init_hart() {
+    write_indirect(EIDELIVERY, 1);
+    write_indirect(EITHRESHOLD, 0);
+    let identities = IDENTITIES.load(Ordering::Relaxed);
+    // On RV64 only even-numbered `eie` registers exist, each of 64 bits
+    for word in 0..=identities / XLEN {
+        write_indirect(EIE0 + word * (XLEN / 32), usize::MAX);
+    }
+}
+
+/// Whether [`init`] found AIA, so external interrupts come from the IMSIC
+#[inline]
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Access the APLIC at `base` from now on, e.g. after mapping it to a virtual address
+#[inline]
+pub fn set_aplic_base(base: usize) {
+    APLIC_BASE.store(base, Ordering::Relaxed);
+}
+
+/// Set trigger of APLIC source `irq`, activating it
+pub fn set_trigger(irq: usize, trigger: Trigger) -> Result<(), AiaError> {
+    check(irq)?;
+    write(SOURCECFG + (irq - 1) * 4, trigger as u32);
+    Ok(())
+}
+
+/// Whether APLIC source `irq` is delegated to a child domain, so it can't be used here
+pub fn is_delegated(irq: usize) -> Result<bool, AiaError> {
+    check(irq)?;
+    Ok(read(SOURCECFG + (irq - 1) * 4) & SOURCECFG_DELEGATE != 0)
+}
+
+/// Send APLIC source `irq` to current hart and enable it
+#[inline]
+pub fn enable(irq: usize) -> Result<(), AiaError> {
+    set_affinity(irq, hart::hart_id())
+}
+
+/// Disable APLIC source `irq`
+pub fn disable(irq: usize) -> Result<(), AiaError> {
+    check(irq)?;
+    write(CLRIENUM, irq as u32);
+    Ok(())
+}
+
+/// Send APLIC source `irq` to hart `hartid` and enable it
+///
+/// An MSI goes to a single hart, unlike PLIC interrupts.
+pub fn set_affinity(irq: usize, hartid: usize) -> Result<(), AiaError> {
+    check(irq)?;
+    if hartid > crate::max_hart_id() {
+        return Err(AiaError::NoInterruptFile(hartid));
+    }
+    let index = unsafe { &*HART_INDEX.as_ptr_of(hartid) }.load(Ordering::Relaxed);
+    if index == NO_HART_INDEX {
+        return Err(AiaError::NoInterruptFile(hartid));
+    }
+    // Interrupt identity is the source number, in guest index 0
+    write(TARGET + (irq - 1) * 4, (index << TARGET_HART_SHIFT | irq) as u32);
+    write(SETIENUM, irq as u32);
+    Ok(())
+}
+
+/// Set the identity threshold of current hart; only identities below it are delivered,
+/// 0 delivers all
+pub fn set_threshold(threshold: usize) {
+    write_indirect(EITHRESHOLD, threshold);
+}
+
+/// Claim the highest priority pending interrupt identity of current hart
+#[inline]
+pub fn claim() -> Option<usize> {
+    // Reading and writing `stopei` at once clears the reported identity
+    let top: usize;
+    unsafe { llvm_asm!("csrrw $0, 0x15c, zero" : "=r"(top) ::: "volatile") };
+    match top >> 16 {
+        0 => None,
+        identity => Some(identity),
+    }
+}
+
+/// Claim and handle all pending interrupts of current hart
+///
+/// Called by the default `SupervisorExternal` handler if [`is_active`]. Identities without
+/// a handler registered with [`external_interrupt`](crate::external_interrupt) are logged
+/// and disabled in the interrupt file of current hart.
+pub fn handle_interrupt() {
+    while let Some(identity) = claim() {
+        match crate::plic::handler(identity) {
+            Some(handler) => handler(),
+            None => {
+                crate::logger::warn_in_interrupt(format_args!(
+                    "unhandled external interrupt {}, disabled",
+                    identity
+                ));
+                // On RV64 only even-numbered `eie` registers exist, each of 64 bits
+                let register = EIE0 + identity / XLEN * (XLEN / 32);
+                clear_indirect(register, 1 << (identity % XLEN));
+            }
+        }
+    }
+}
+
+// Sources are sent as the identity of the same number, so both limits apply
+#[inline]
+fn check(irq: usize) -> Result<(), AiaError> {
+    if irq != 0
+        && irq <= SOURCES.load(Ordering::Relaxed)
+        && irq <= IDENTITIES.load(Ordering::Relaxed)
+    {
+        Ok(())
+    } else {
+        Err(AiaError::InvalidIrq(irq))
+    }
+}
+
+fn write_indirect(register: usize, value: usize) {
+    // `siselect`, then `sireg`; interrupts may select another register in between
+    crate::without_interrupts(|| unsafe {
+        llvm_asm!("
+            csrw    0x150, $0
+            csrw    0x151, $1
+        " :: "r"(register), "r"(value) :: "volatile");
+    });
+}
+
+fn clear_indirect(register: usize, bits: usize) {
+    crate::without_interrupts(|| unsafe {
+        llvm_asm!("
+            csrw    0x150, $0
+            csrc    0x151, $1
+        " :: "r"(register), "r"(bits) :: "volatile");
+    });
+}
+
+#[inline]
+fn read(offset: usize) -> u32 {
+    let base = APLIC_BASE.load(Ordering::Relaxed);
+    unsafe { ((base + offset) as *const u32).read_volatile() }
+}
+
+#[inline]
+fn write(offset: usize, value: u32) {
+    let base = APLIC_BASE.load(Ordering::Relaxed);
+    unsafe { ((base + offset) as *mut u32).write_volatile(value) }
+}
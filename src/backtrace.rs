@@ -0,0 +1,131 @@
+//! Frame pointer based stack backtrace
+//!
+//! Build with `-C force-frame-pointers=yes`, so every function keeps its return address
+//! and the caller's frame pointer right below its frame pointer `s0`:
+//!
+//! ```text
+//! s0 - 1 word: return address
+//! s0 - 2 words: frame pointer of the caller
+//! ```
+//!
+//! Unwinding through `_start_trap_sbi` continues into the interrupted code, as the trap
+//! entry keeps `s0`. Return addresses are symbolized if the symbol table of a previous
+//! build is embedded, see the README.
+
+use crate::TrapFrame;
+use crate::println;
+
+const WORD: usize = core::mem::size_of::<usize>();
+const MAX_DEPTH: usize = 64;
+
+// Records of `address: u64, name length: u32, name` sorted by address, little endian
+static SYMBOLS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin"));
+
+/// Call `f` with each return address from frame pointer `fp` outwards
+///
+/// Stops at a frame pointer outside of the hart and trap stacks.
+pub fn trace(mut fp: usize, mut f: impl FnMut(usize)) {
+    for _ in 0..MAX_DEPTH {
+        if fp % WORD != 0 || !on_stack(fp.wrapping_sub(2 * WORD)) {
+            break;
+        }
+        let (ra, caller_fp) = unsafe {
+            let record = fp as *const usize;
+            (*record.sub(1), *record.sub(2))
+        };
+        if ra == 0 {
+            break;
+        }
+        f(ra);
+        // Frames are deeper in the stack than their callers
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+}
+
+/// Print the backtrace of the caller
+#[inline(always)]
+pub fn print() {
+    let fp: usize;
+    unsafe { llvm_asm!("mv $0, s0" : "=r"(fp)) };
+    println!("backtrace:");
+    let mut depth = 0;
+    trace(fp, |pc| {
+        print_frame(depth, pc);
+        depth += 1;
+    });
+}
+
+/// Print the backtrace of the code interrupted by a trap, from its `sepc` and `s0`
+///
+/// Called by the default exception handler before it panics.
+pub fn print_trap(trap_frame: &TrapFrame) {
+    println!("trap backtrace:");
+    print_frame(0, trap_frame.sepc);
+    let mut depth = 1;
+    trace(trap_frame.s0, |pc| {
+        print_frame(depth, pc);
+        depth += 1;
+    });
+}
+
+fn print_frame(depth: usize, pc: usize) {
+    match symbolize(pc) {
+        Some((name, offset)) => println!("  #{} {:#x} {}+{:#x}", depth, pc, name, offset),
+        None => println!("  #{} {:#x}", depth, pc),
+    }
+}
+
+/// Find the symbol containing runtime address `pc`, with offset of `pc` in it
+///
+/// The symbol table holds linked addresses; with `pie` or `kaslr`, `pc` is moved back by
+/// the offset the kernel runs at from them.
+pub fn symbolize(pc: usize) -> Option<(&'static str, usize)> {
+    let pc = pc.wrapping_sub(load_offset());
+    let mut best = None;
+    let mut rest = SYMBOLS;
+    while rest.len() >= 12 {
+        let mut address = [0; 8];
+        address.copy_from_slice(&rest[..8]);
+        let address = u64::from_le_bytes(address) as usize;
+        let mut len = [0; 4];
+        len.copy_from_slice(&rest[8..12]);
+        let len = u32::from_le_bytes(len) as usize;
+        let name = rest.get(12..12 + len)?;
+        if address > pc {
+            break;
+        }
+        best = Some((name, address));
+        rest = &rest[12 + len..];
+    }
+    let (name, address) = best?;
+    Some((core::str::from_utf8(name).ok()?, pc - address))
+}
+
+// Offset of runtime addresses from linked ones
+#[cfg(feature = "pie")]
+#[inline]
+fn load_offset() -> usize {
+    crate::reloc::load_base().wrapping_sub(crate::reloc::link_base())
+}
+
+#[cfg(not(feature = "pie"))]
+#[inline]
+fn load_offset() -> usize {
+    0
+}
+
+fn on_stack(addr: usize) -> bool {
+    extern "C" {
+        static _estack: u8;
+        static _sstack: u8;
+        static _etrap_stack: u8;
+        static _strap_stack: u8;
+    }
+    let within = |start: &u8, end: &u8| {
+        addr >= start as *const u8 as usize && addr < end as *const u8 as usize
+    };
+    unsafe { within(&_estack, &_sstack) || within(&_etrap_stack, &_strap_stack) }
+}
@@ -0,0 +1,312 @@
+//! Console behind [`print!`](crate::print) and [`println!`](crate::println)
+//!
+//! Output goes to the SBI console, with DBCN if available, until a 16550 UART is set up.
+//! At boot without paging, the runtime sets up the UART of `/chosen/stdout-path` with
+//! [`init`], and keeps the SBI console if there's none or it doesn't respond. The boot page
+//! doesn't map devices by itself: map the UART in it and call [`init_mapped`], or call
+//! [`set_uart`] with its mapped address.
+//!
+//! Each print holds a console lock, so prints of different harts don't interleave.
+//! [`putchar`] and [`write_str`] don't take it.
+//!
+//! Input is polled by [`getchar`], or received into a ring buffer by the interrupt handler
+//! registered with [`enable_rx_interrupt`].
+
+use crate::fdt::{Fdt, Node};
+use crate::uart::Uart16550;
+use crate::{aia, hart, plic, sbi};
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+use riscv::register::sstatus;
+
+/// Size of the buffer for received bytes
+pub const RX_BUFFER_SIZE: usize = 256;
+
+// UART registers, zero base for the SBI console
+static UART_BASE: AtomicUsize = AtomicUsize::new(0);
+static UART_SHIFT: AtomicUsize = AtomicUsize::new(0);
+static UART_WIDTH: AtomicUsize = AtomicUsize::new(1);
+// Interrupt of the UART in the device tree, zero if none
+static UART_IRQ: AtomicUsize = AtomicUsize::new(0);
+
+// Received bytes; one producer, the interrupt handler claiming the UART interrupt
+struct RxBuffer {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    bytes: UnsafeCell<[u8; RX_BUFFER_SIZE]>,
+}
+
+unsafe impl Sync for RxBuffer {}
+
+static RX: RxBuffer = RxBuffer {
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+    bytes: UnsafeCell::new([0; RX_BUFFER_SIZE]),
+};
+// Whether received bytes go to `RX`
+static RX_INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Use the UART of `/chosen/stdout-path` if it's a responding 16550
+///
+/// The UART is accessed at its address in the device tree.
+#[inline]
+pub fn init(fdt: &Fdt) -> bool {
+    init_mapped(fdt, 0)
+}
+
+/// Like [`init`], with the UART accessed at its address in the device tree plus `offset`
+///
+/// E.g. `0xffffffff_00000000` with the boot page entry
+/// `(0xffffffff_00000000 => 0x00000000_00000000, rw)`.
+pub fn init_mapped(fdt: &Fdt, offset: usize) -> bool {
+    let node = match stdout_node(fdt) {
+        Some(node) => node,
+        None => return false,
+    };
+    let uart = match Uart16550::from_node(&node) {
+        Some(uart) => uart.with_base(uart.base().wrapping_add(offset)),
+        None => return false,
+    };
+    if !uart.init() {
+        return false;
+    }
+    let irq = node.property_u32("interrupts").unwrap_or(0);
+    UART_IRQ.store(irq as usize, Ordering::Relaxed);
+    set_uart(Some(uart));
+    true
+}
+
+// Node of `stdout-path`, a full path or an alias, with optional `:options`
+fn stdout_node<'a>(fdt: &Fdt<'a>) -> Option<Node<'a>> {
+    let chosen = fdt.chosen()?;
+    let path = chosen
+        .property_str("stdout-path")
+        .or_else(|| chosen.property_str("linux,stdout-path"))?;
+    let path = path.split(':').next()?;
+    if path.starts_with('/') {
+        fdt.find_node(path)
+    } else {
+        let path = fdt.find_node("/aliases")?.property_str(path)?;
+        fdt.find_node(path)
+    }
+}
+
+/// Write to `uart` from now on, or to the SBI console if `None`
+///
+/// The UART must be set up already, e.g. by [`Uart16550::init`].
+pub fn set_uart(uart: Option<Uart16550>) {
+    let uart = uart.unwrap_or_else(|| Uart16550::new(0, 0, 1));
+    UART_SHIFT.store(uart.shift(), Ordering::Relaxed);
+    UART_WIDTH.store(uart.width(), Ordering::Relaxed);
+    UART_BASE.store(uart.base(), Ordering::Release);
+}
+
+/// The UART written to, `None` for the SBI console
+pub fn uart() -> Option<Uart16550> {
+    match UART_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(Uart16550::new(
+            base,
+            UART_SHIFT.load(Ordering::Relaxed),
+            UART_WIDTH.load(Ordering::Relaxed),
+        )),
+    }
+}
+
+/// Write a byte; `\n` is written as `\r\n` to the UART
+pub fn putchar(byte: u8) {
+    match uart() {
+        Some(uart) => {
+            if byte == b'\n' {
+                uart.putchar(b'\r');
+            }
+            uart.putchar(byte)
+        }
+        None => sbi::console_putchar(byte),
+    }
+}
+
+/// Write a string
+///
+/// The SBI console gets whole strings with DBCN, see [`sbi::console_write`].
+pub fn write_str(s: &str) {
+    if uart().is_some() {
+        s.bytes().for_each(putchar);
+        return;
+    }
+    let mut bytes = s.as_bytes();
+    while !bytes.is_empty() {
+        match sbi::console_write(bytes) {
+            Ok(written) if written > 0 => bytes = &bytes[written.min(bytes.len())..],
+            // Busy or failed console, try byte by byte
+            _ => {
+                bytes.iter().for_each(|&byte| sbi::console_putchar(byte));
+                return;
+            }
+        }
+    }
+}
+
+/// Read a received byte, if any
+pub fn getchar() -> Option<u8> {
+    if RX_INTERRUPT.load(Ordering::Acquire) {
+        return pop_rx();
+    }
+    match uart() {
+        Some(uart) => uart.getchar(),
+        None => sbi::console_getchar(),
+    }
+}
+
+/// Receive bytes of the UART in an interrupt handler, buffering up to [`RX_BUFFER_SIZE`]
+///
+/// Enables the UART interrupt in the PLIC or APLIC for current hart, whichever is
+/// initialized. Returns false without a UART interrupt in the device tree, if the handler
+/// can't be registered, or if the interrupt can't be enabled, e.g. on a hart without a PLIC
+/// context or an IMSIC interrupt file. Bytes arriving to a full buffer are dropped.
+pub fn enable_rx_interrupt() -> bool {
+    let irq = UART_IRQ.load(Ordering::Relaxed);
+    let uart = match uart() {
+        Some(uart) if irq != 0 => uart,
+        _ => return false,
+    };
+    if !plic::register_handler(irq, on_rx_interrupt) {
+        return false;
+    }
+    let enabled = if aia::is_active() {
+        aia::set_trigger(irq, aia::Trigger::LevelHigh)
+            .and_then(|_| aia::enable(irq))
+            .is_ok()
+    } else {
+        plic::set_priority(irq, 1)
+            .and_then(|_| plic::enable(irq))
+            .is_ok()
+    };
+    if !enabled {
+        return false;
+    }
+    RX_INTERRUPT.store(true, Ordering::Release);
+    uart.enable_rx_interrupt();
+    true
+}
+
+fn on_rx_interrupt() {
+    let uart = match uart() {
+        Some(uart) => uart,
+        None => return,
+    };
+    while let Some(byte) = uart.getchar() {
+        let tail = RX.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(RX.head.load(Ordering::Acquire)) == RX_BUFFER_SIZE {
+            continue;
+        }
+        unsafe { (*RX.bytes.get())[tail % RX_BUFFER_SIZE] = byte };
+        RX.tail.store(tail.wrapping_add(1), Ordering::Release);
+    }
+}
+
+fn pop_rx() -> Option<u8> {
+    let mut head = RX.head.load(Ordering::Relaxed);
+    loop {
+        if head == RX.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = unsafe { (*RX.bytes.get())[head % RX_BUFFER_SIZE] };
+        match RX.head.compare_exchange_weak(
+            head,
+            head.wrapping_add(1),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return Some(byte),
+            Err(current) => head = current,
+        }
+    }
+}
+
+// Hart id + 1 of the hart printing, zero if none
+static OWNER: AtomicUsize = AtomicUsize::new(0);
+
+// Holds the console lock, with interrupts disabled
+struct Guard {
+    interrupts: bool,
+    reentrant: bool,
+}
+
+// Lock the console for one print; a trap handler or panic interrupting a print on
+// the same hart prints within it instead of deadlocking
+fn lock() -> Guard {
+    let owner = hart::hart_id() + 1;
+    loop {
+        let interrupts = sstatus::read().sie();
+        unsafe { sstatus::clear_sie() };
+        match OWNER.compare_exchange(0, owner, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => {
+                return Guard {
+                    interrupts,
+                    reentrant: false,
+                }
+            }
+            Err(current) if current == owner => {
+                return Guard {
+                    interrupts,
+                    reentrant: true,
+                }
+            }
+            Err(_) => {}
+        }
+        // Take interrupts while waiting, e.g. the IPI stopping harts on panic
+        if interrupts {
+            unsafe { sstatus::set_sie() };
+        }
+        spin_loop_hint();
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if !self.reentrant {
+            OWNER.store(0, Ordering::Release);
+        }
+        if self.interrupts {
+            unsafe { sstatus::set_sie() };
+        }
+    }
+}
+
+/// Take the console for current hart for good, even if another hart is printing
+///
+/// Used by the panic handler, so the report isn't blocked by a hart that stopped while
+/// holding the console. Prints of other harts wait forever afterwards.
+pub fn force_lock() {
+    OWNER.store(hart::hart_id() + 1, Ordering::Release);
+}
+
+struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _guard = lock();
+    Stdout.write_fmt(args).unwrap();
+}
+
+/// Print to the console
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+/// Print to the console, with a newline
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::console::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
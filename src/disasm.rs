@@ -0,0 +1,441 @@
+//! Minimal RISC-V disassembler for trap reports
+//!
+//! Decodes the base integer instructions with M, A, Zicsr and Zifencei, privileged
+//! instructions, F and D loads and stores, and the C extension for current XLEN.
+//! Anything else, including RV64-only encodings on RV32 and reserved ones, is printed
+//! as a raw `.word` or `.half`.
+
+use core::fmt;
+
+const REGS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// Predecessor and successor sets of `fence`
+const FENCE_SETS: [&str; 16] = [
+    "0", "w", "r", "rw", "o", "ow", "or", "orw", "i", "iw", "ir", "irw", "io", "iow", "ior", "iorw",
+];
+
+/// An instruction, 16-bit compressed or 32-bit
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Instruction {
+    /// Instruction of the C extension
+    Compressed(u16),
+    /// 32-bit instruction
+    Full(u32),
+}
+
+impl Instruction {
+    /// Read the instruction at `addr`
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be readable for 2 bytes, or 4 bytes if it's not a compressed instruction.
+    pub unsafe fn read(addr: usize) -> Self {
+        let low = (addr as *const u16).read_volatile();
+        if low & 0b11 != 0b11 {
+            Instruction::Compressed(low)
+        } else {
+            let high = (addr as *const u16).add(1).read_volatile();
+            Instruction::Full(u32::from(low) | u32::from(high) << 16)
+        }
+    }
+
+    /// Size of the instruction in bytes
+    #[inline]
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::Compressed(_) => 2,
+            Instruction::Full(_) => 4,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Compressed(c) => fmt_compressed(f, u32::from(c)),
+            Instruction::Full(w) => fmt_full(f, w),
+        }
+    }
+}
+
+// Sign extend the low `bits` bits of `value`
+#[inline]
+fn sext(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+#[inline]
+fn reg(field: u32) -> &'static str {
+    REGS[(field & 0x1f) as usize]
+}
+
+fn csr_name(csr: u32) -> Option<&'static str> {
+    Some(match csr {
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x106 => "scounteren",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x14d => "stimecmp",
+        0x180 => "satp",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        _ => return None,
+    })
+}
+
+fn fmt_full(f: &mut fmt::Formatter, w: u32) -> fmt::Result {
+    let rd = reg(w >> 7);
+    let rs1 = reg(w >> 15);
+    let rs2 = reg(w >> 20);
+    let funct3 = w >> 12 & 7;
+    let funct7 = w >> 25;
+    let imm_i = (w as i32) >> 20;
+    let imm_s = sext((w >> 25) << 5 | (w >> 7 & 0x1f), 12);
+    let imm_b = sext(
+        (w >> 31) << 12 | (w >> 7 & 1) << 11 | (w >> 25 & 0x3f) << 5 | (w >> 8 & 0xf) << 1,
+        13,
+    );
+    let imm_j = sext(
+        (w >> 31) << 20 | (w >> 12 & 0xff) << 12 | (w >> 20 & 1) << 11 | (w >> 21 & 0x3ff) << 1,
+        21,
+    );
+    let raw = |f: &mut fmt::Formatter| write!(f, ".word {:#010x}", w);
+
+    match w & 0x7f {
+        0x37 => write!(f, "lui {}, {:#x}", rd, w >> 12),
+        0x17 => write!(f, "auipc {}, {:#x}", rd, w >> 12),
+        0x6f => write!(f, "jal {}, {}", rd, imm_j),
+        0x67 if funct3 == 0 => write!(f, "jalr {}, {}({})", rd, imm_i, rs1),
+        0x63 => {
+            let op = match funct3 {
+                0 => "beq",
+                1 => "bne",
+                4 => "blt",
+                5 => "bge",
+                6 => "bltu",
+                7 => "bgeu",
+                _ => return raw(f),
+            };
+            write!(f, "{} {}, {}, {}", op, rs1, rs2, imm_b)
+        }
+        0x03 => {
+            let op = match funct3 {
+                0 => "lb",
+                1 => "lh",
+                2 => "lw",
+                #[cfg(target_pointer_width = "64")]
+                3 => "ld",
+                4 => "lbu",
+                5 => "lhu",
+                #[cfg(target_pointer_width = "64")]
+                6 => "lwu",
+                _ => return raw(f),
+            };
+            write!(f, "{} {}, {}({})", op, rd, imm_i, rs1)
+        }
+        0x23 => {
+            let op = match funct3 {
+                0 => "sb",
+                1 => "sh",
+                2 => "sw",
+                #[cfg(target_pointer_width = "64")]
+                3 => "sd",
+                _ => return raw(f),
+            };
+            write!(f, "{} {}, {}({})", op, rs2, imm_s, rs1)
+        }
+        0x07 if funct3 == 2 || funct3 == 3 => {
+            let op = if funct3 == 2 { "flw" } else { "fld" };
+            write!(f, "{} f{}, {}({})", op, w >> 7 & 0x1f, imm_i, rs1)
+        }
+        0x27 if funct3 == 2 || funct3 == 3 => {
+            let op = if funct3 == 2 { "fsw" } else { "fsd" };
+            write!(f, "{} f{}, {}({})", op, w >> 20 & 0x1f, imm_s, rs1)
+        }
+        0x13 => match funct3 {
+            0 => write!(f, "addi {}, {}, {}", rd, rs1, imm_i),
+            2 => write!(f, "slti {}, {}, {}", rd, rs1, imm_i),
+            3 => write!(f, "sltiu {}, {}, {}", rd, rs1, imm_i),
+            4 => write!(f, "xori {}, {}, {}", rd, rs1, imm_i),
+            6 => write!(f, "ori {}, {}, {}", rd, rs1, imm_i),
+            7 => write!(f, "andi {}, {}, {}", rd, rs1, imm_i),
+            _ => {
+                // 6-bit shift amount on RV64, so funct6 is compared as funct7 without bit 0
+                #[cfg(target_pointer_width = "64")]
+                let (shamt, funct7) = (w >> 20 & 0x3f, w >> 26 << 1);
+                #[cfg(target_pointer_width = "32")]
+                let (shamt, funct7) = (w >> 20 & 0x1f, funct7);
+                let op = match (funct3, funct7) {
+                    (1, 0) => "slli",
+                    (5, 0) => "srli",
+                    (5, 0x20) => "srai",
+                    _ => return raw(f),
+                };
+                write!(f, "{} {}, {}, {}", op, rd, rs1, shamt)
+            }
+        },
+        #[cfg(target_pointer_width = "64")]
+        0x1b => {
+            let shamt = w >> 20 & 0x1f;
+            match (funct3, funct7) {
+                (0, _) => write!(f, "addiw {}, {}, {}", rd, rs1, imm_i),
+                (1, 0) => write!(f, "slliw {}, {}, {}", rd, rs1, shamt),
+                (5, 0) => write!(f, "srliw {}, {}, {}", rd, rs1, shamt),
+                (5, 0x20) => write!(f, "sraiw {}, {}, {}", rd, rs1, shamt),
+                _ => raw(f),
+            }
+        }
+        0x33 => {
+            let op = match (funct7, funct3) {
+                (0, 0) => "add",
+                (0, 1) => "sll",
+                (0, 2) => "slt",
+                (0, 3) => "sltu",
+                (0, 4) => "xor",
+                (0, 5) => "srl",
+                (0, 6) => "or",
+                (0, 7) => "and",
+                (0x20, 0) => "sub",
+                (0x20, 5) => "sra",
+                (1, 0) => "mul",
+                (1, 1) => "mulh",
+                (1, 2) => "mulhsu",
+                (1, 3) => "mulhu",
+                (1, 4) => "div",
+                (1, 5) => "divu",
+                (1, 6) => "rem",
+                (1, 7) => "remu",
+                _ => return raw(f),
+            };
+            write!(f, "{} {}, {}, {}", op, rd, rs1, rs2)
+        }
+        #[cfg(target_pointer_width = "64")]
+        0x3b => {
+            let op = match (funct7, funct3) {
+                (0, 0) => "addw",
+                (0, 1) => "sllw",
+                (0, 5) => "srlw",
+                (0x20, 0) => "subw",
+                (0x20, 5) => "sraw",
+                (1, 0) => "mulw",
+                (1, 4) => "divw",
+                (1, 5) => "divuw",
+                (1, 6) => "remw",
+                (1, 7) => "remuw",
+                _ => return raw(f),
+            };
+            write!(f, "{} {}, {}, {}", op, rd, rs1, rs2)
+        }
+        0x0f => match w {
+            0x8330_000f => write!(f, "fence.tso"),
+            0x0000_100f => write!(f, "fence.i"),
+            // fm, rs1 and rd are zero
+            _ if w & 0xf00f_ff80 == 0 => {
+                let pred = FENCE_SETS[(w >> 24 & 0xf) as usize];
+                let succ = FENCE_SETS[(w >> 20 & 0xf) as usize];
+                write!(f, "fence {}, {}", pred, succ)
+            }
+            _ => raw(f),
+        },
+        0x2f if funct3 == 2 || (funct3 == 3 && cfg!(target_pointer_width = "64")) => {
+            let width = if funct3 == 2 { "w" } else { "d" };
+            // aq and rl bits
+            let order = ["", ".rl", ".aq", ".aqrl"][(w >> 25 & 3) as usize];
+            let op = match w >> 27 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 if w >> 20 & 0x1f == 0 => {
+                    return write!(f, "lr.{}{} {}, ({})", width, order, rd, rs1)
+                }
+                0x03 => return write!(f, "sc.{}{} {}, {}, ({})", width, order, rd, rs2, rs1),
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0c => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return raw(f),
+            };
+            write!(f, "{}.{}{} {}, {}, ({})", op, width, order, rd, rs2, rs1)
+        }
+        0x73 => {
+            let csr = w >> 20;
+            let uimm = w >> 15 & 0x1f;
+            let op = match funct3 {
+                0 => {
+                    return match w {
+                        0x0000_0073 => write!(f, "ecall"),
+                        0x0010_0073 => write!(f, "ebreak"),
+                        0x1020_0073 => write!(f, "sret"),
+                        0x3020_0073 => write!(f, "mret"),
+                        0x1050_0073 => write!(f, "wfi"),
+                        _ if funct7 == 0x09 && w >> 7 & 0x1f == 0 => {
+                            write!(f, "sfence.vma {}, {}", rs1, rs2)
+                        }
+                        _ => raw(f),
+                    }
+                }
+                1 => "csrrw",
+                2 => "csrrs",
+                3 => "csrrc",
+                5 => "csrrwi",
+                6 => "csrrsi",
+                7 => "csrrci",
+                _ => return raw(f),
+            };
+            write!(f, "{} {}, ", op, rd)?;
+            match csr_name(csr) {
+                Some(name) => write!(f, "{}", name)?,
+                None => write!(f, "{:#x}", csr)?,
+            }
+            if funct3 < 4 {
+                write!(f, ", {}", rs1)
+            } else {
+                write!(f, ", {}", uimm)
+            }
+        }
+        _ => raw(f),
+    }
+}
+
+fn fmt_compressed(f: &mut fmt::Formatter, c: u32) -> fmt::Result {
+    let funct3 = c >> 13;
+    let rd = reg(c >> 7);
+    let rs2 = reg(c >> 2);
+    // 3-bit register fields of x8 to x15
+    let rd_ = REGS[8 + (c >> 2 & 7) as usize];
+    let rs1_ = REGS[8 + (c >> 7 & 7) as usize];
+    let imm6 = sext((c >> 7 & 0x20) | (c >> 2 & 0x1f), 6);
+    let shamt = (c >> 7 & 0x20) | (c >> 2 & 0x1f);
+    // Offsets of word and double word loads and stores
+    let uimm_w = (c >> 7 & 0x38) | (c >> 4 & 4) | (c << 1 & 0x40);
+    let uimm_d = (c >> 7 & 0x38) | (c << 1 & 0xc0);
+    let uimm_lwsp = (c >> 7 & 0x20) | (c >> 2 & 0x1c) | (c << 4 & 0xc0);
+    let uimm_ldsp = (c >> 7 & 0x20) | (c >> 2 & 0x18) | (c << 4 & 0x1c0);
+    let uimm_swsp = (c >> 7 & 0x3c) | (c >> 1 & 0xc0);
+    let uimm_sdsp = (c >> 7 & 0x38) | (c >> 1 & 0x1c0);
+    let raw = |f: &mut fmt::Formatter| write!(f, ".half {:#06x}", c);
+
+    match (c & 3, funct3) {
+        (0, 0) => {
+            let uimm = (c >> 7 & 0x30) | (c >> 1 & 0x3c0) | (c >> 4 & 4) | (c >> 2 & 8);
+            if uimm == 0 {
+                return raw(f);
+            }
+            write!(f, "c.addi4spn {}, sp, {}", rd_, uimm)
+        }
+        (0, 1) => write!(f, "c.fld f{}, {}({})", 8 + (c >> 2 & 7), uimm_d, rs1_),
+        (0, 2) => write!(f, "c.lw {}, {}({})", rd_, uimm_w, rs1_),
+        #[cfg(target_pointer_width = "64")]
+        (0, 3) => write!(f, "c.ld {}, {}({})", rd_, uimm_d, rs1_),
+        #[cfg(target_pointer_width = "32")]
+        (0, 3) => write!(f, "c.flw f{}, {}({})", 8 + (c >> 2 & 7), uimm_w, rs1_),
+        (0, 5) => write!(f, "c.fsd f{}, {}({})", 8 + (c >> 2 & 7), uimm_d, rs1_),
+        (0, 6) => write!(f, "c.sw {}, {}({})", rd_, uimm_w, rs1_),
+        #[cfg(target_pointer_width = "64")]
+        (0, 7) => write!(f, "c.sd {}, {}({})", rd_, uimm_d, rs1_),
+        #[cfg(target_pointer_width = "32")]
+        (0, 7) => write!(f, "c.fsw f{}, {}({})", 8 + (c >> 2 & 7), uimm_w, rs1_),
+        (1, 0) if c >> 7 & 0x1f == 0 && imm6 == 0 => write!(f, "c.nop"),
+        (1, 0) if c >> 7 & 0x1f == 0 => write!(f, "c.nop {}", imm6),
+        (1, 0) => write!(f, "c.addi {}, {}", rd, imm6),
+        #[cfg(target_pointer_width = "64")]
+        (1, 1) if c >> 7 & 0x1f != 0 => write!(f, "c.addiw {}, {}", rd, imm6),
+        #[cfg(target_pointer_width = "32")]
+        (1, 1) => write!(f, "c.jal {}", jump_offset(c)),
+        (1, 2) => write!(f, "c.li {}, {}", rd, imm6),
+        (1, 3) if c >> 7 & 0x1f == 2 => {
+            let nzimm = (c >> 3 & 0x200)
+                | (c >> 2 & 0x10)
+                | (c << 1 & 0x40)
+                | (c << 4 & 0x180)
+                | (c << 3 & 0x20);
+            if nzimm == 0 {
+                return raw(f);
+            }
+            write!(f, "c.addi16sp sp, {}", sext(nzimm, 10))
+        }
+        (1, 3) => write!(f, "c.lui {}, {:#x}", rd, imm6 as u32 & 0xfffff),
+        (1, 4) => match c >> 10 & 3 {
+            0 | 1 => {
+                let op = if c >> 10 & 3 == 0 { "c.srli" } else { "c.srai" };
+                if shamt == 0 {
+                    return write!(f, "{}64 {}", op, rs1_);
+                }
+                write!(f, "{} {}, {}", op, rs1_, shamt)
+            }
+            2 => write!(f, "c.andi {}, {}", rs1_, imm6),
+            _ => {
+                let op = match (c >> 12 & 1, c >> 5 & 3) {
+                    (0, 0) => "c.sub",
+                    (0, 1) => "c.xor",
+                    (0, 2) => "c.or",
+                    (0, 3) => "c.and",
+                    #[cfg(target_pointer_width = "64")]
+                    (1, 0) => "c.subw",
+                    #[cfg(target_pointer_width = "64")]
+                    (1, 1) => "c.addw",
+                    _ => return raw(f),
+                };
+                write!(f, "{} {}, {}", op, rs1_, rd_)
+            }
+        },
+        (1, 5) => write!(f, "c.j {}", jump_offset(c)),
+        (1, 6) | (1, 7) => {
+            let op = if funct3 == 6 { "c.beqz" } else { "c.bnez" };
+            let offset = (c >> 4 & 0x100)
+                | (c >> 7 & 0x18)
+                | (c << 1 & 0xc0)
+                | (c >> 2 & 6)
+                | (c << 3 & 0x20);
+            write!(f, "{} {}, {}", op, rs1_, sext(offset, 9))
+        }
+        (2, 0) if shamt == 0 => write!(f, "c.slli64 {}", rd),
+        (2, 0) => write!(f, "c.slli {}, {}", rd, shamt),
+        (2, 1) => write!(f, "c.fldsp f{}, {}(sp)", c >> 7 & 0x1f, uimm_ldsp),
+        (2, 2) if c >> 7 & 0x1f != 0 => write!(f, "c.lwsp {}, {}(sp)", rd, uimm_lwsp),
+        #[cfg(target_pointer_width = "64")]
+        (2, 3) if c >> 7 & 0x1f != 0 => write!(f, "c.ldsp {}, {}(sp)", rd, uimm_ldsp),
+        #[cfg(target_pointer_width = "32")]
+        (2, 3) => write!(f, "c.flwsp f{}, {}(sp)", c >> 7 & 0x1f, uimm_lwsp),
+        (2, 4) => match (c >> 12 & 1, c >> 7 & 0x1f, c >> 2 & 0x1f) {
+            (0, 0, 0) => raw(f),
+            (0, _, 0) => write!(f, "c.jr {}", rd),
+            (0, _, _) => write!(f, "c.mv {}, {}", rd, rs2),
+            (1, 0, 0) => write!(f, "c.ebreak"),
+            (1, _, 0) => write!(f, "c.jalr {}", rd),
+            _ => write!(f, "c.add {}, {}", rd, rs2),
+        },
+        (2, 5) => write!(f, "c.fsdsp f{}, {}(sp)", c >> 2 & 0x1f, uimm_sdsp),
+        (2, 6) => write!(f, "c.swsp {}, {}(sp)", rs2, uimm_swsp),
+        #[cfg(target_pointer_width = "64")]
+        (2, 7) => write!(f, "c.sdsp {}, {}(sp)", rs2, uimm_sdsp),
+        #[cfg(target_pointer_width = "32")]
+        (2, 7) => write!(f, "c.fswsp f{}, {}(sp)", c >> 2 & 0x1f, uimm_swsp),
+        _ => raw(f),
+    }
+}
+
+// Offset of `c.j` and `c.jal`
+fn jump_offset(c: u32) -> i32 {
+    let offset = (c >> 1 & 0x800)
+        | (c >> 7 & 0x10)
+        | (c >> 1 & 0x300)
+        | (c << 2 & 0x400)
+        | (c >> 1 & 0x40)
+        | (c << 1 & 0x80)
+        | (c >> 2 & 0xe)
+        | (c << 3 & 0x20);
+    sext(offset, 12)
+}
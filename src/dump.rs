@@ -0,0 +1,145 @@
+//! Register dump of a trap, used by the default trap handlers
+
+use crate::disasm::Instruction;
+use crate::TrapFrame;
+use core::fmt;
+use riscv::register::{scause, sstatus, stval};
+
+const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const INTERRUPT_BIT: usize = 1 << (core::mem::size_of::<usize>() * 8 - 1);
+const PAGE_SIZE: usize = 4096;
+
+/// Formats a trap: the cause, `sepc`, `stval`, decoded `sstatus`, the instruction at `sepc`
+/// and all general purpose registers
+///
+/// `scause` and `stval` are read when it's created, so create it in the trap handler.
+pub struct TrapDump<'a> {
+    trap_frame: &'a TrapFrame,
+    scause: usize,
+    stval: usize,
+}
+
+impl<'a> TrapDump<'a> {
+    /// Dump of the trap being handled, saved in `trap_frame`
+    pub fn new(trap_frame: &'a TrapFrame) -> Self {
+        TrapDump {
+            trap_frame,
+            scause: scause::read().bits(),
+            stval: stval::read(),
+        }
+    }
+
+    // The instruction at `sepc` can't be read after faults fetching it, nor in user memory,
+    // which faults without `SUM`; a fault here would nest in the panic report
+    fn instruction(&self) -> Option<Instruction> {
+        let sepc = self.trap_frame.sepc;
+        if matches!(self.scause, 0 | 1 | 12)
+            || matches!(self.trap_frame.sstatus.spp(), sstatus::SPP::User)
+        {
+            return None;
+        }
+        let low = unsafe { (sepc as *const u16).read_volatile() };
+        // The second half of a full instruction may be on an unmapped page
+        if low & 0b11 == 0b11 && (sepc + 2) % PAGE_SIZE == 0 {
+            return None;
+        }
+        Some(unsafe { Instruction::read(sepc) })
+    }
+}
+
+impl TrapFrame {
+    /// Dump of this trap, see [`TrapDump`]
+    #[inline]
+    pub fn dump(&self) -> TrapDump {
+        TrapDump::new(self)
+    }
+}
+
+impl<'a> fmt::Display for TrapDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = self.trap_frame;
+        writeln!(f, "scause: {:#x} ({})", self.scause, cause_name(self.scause))?;
+        writeln!(f, "sepc:   {:#x}", frame.sepc)?;
+        writeln!(f, "stval:  {:#x}", self.stval)?;
+
+        let status = frame.sstatus;
+        let spp = match status.spp() {
+            sstatus::SPP::Supervisor => "S",
+            sstatus::SPP::User => "U",
+        };
+        let fs = match status.fs() {
+            sstatus::FS::Off => "off",
+            sstatus::FS::Initial => "initial",
+            sstatus::FS::Clean => "clean",
+            sstatus::FS::Dirty => "dirty",
+        };
+        writeln!(
+            f,
+            "sstatus: SPP={} SPIE={} SIE={} SUM={} MXR={} FS={}",
+            spp,
+            status.spie() as u8,
+            status.sie() as u8,
+            status.sum() as u8,
+            status.mxr() as u8,
+            fs
+        )?;
+
+        match self.instruction() {
+            Some(Instruction::Compressed(raw)) => {
+                writeln!(f, "insn:   {:#06x}     {}", raw, Instruction::Compressed(raw))?
+            }
+            Some(Instruction::Full(raw)) => {
+                writeln!(f, "insn:   {:#010x} {}", raw, Instruction::Full(raw))?
+            }
+            None => writeln!(f, "insn:   <not readable>")?,
+        }
+
+        // Fields before `sstatus` are x0 to x31
+        let regs = unsafe { &*(frame as *const TrapFrame as *const [usize; 32]) };
+        for (i, (name, value)) in ABI_NAMES.iter().zip(regs.iter()).enumerate() {
+            write!(f, "{:>4}: {:#018x}", name, value)?;
+            if i % 4 == 3 {
+                writeln!(f)?;
+            } else {
+                write!(f, "  ")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn cause_name(scause: usize) -> &'static str {
+    if scause & INTERRUPT_BIT != 0 {
+        return match scause & !INTERRUPT_BIT {
+            0 => "user software interrupt",
+            1 => "supervisor software interrupt",
+            4 => "user timer interrupt",
+            5 => "supervisor timer interrupt",
+            8 => "user external interrupt",
+            9 => "supervisor external interrupt",
+            13 => "counter overflow interrupt",
+            _ => "unknown interrupt",
+        };
+    }
+    match scause {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store/AMO address misaligned",
+        7 => "store/AMO access fault",
+        8 => "environment call from U-mode",
+        9 => "environment call from S-mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store/AMO page fault",
+        _ => "unknown exception",
+    }
+}
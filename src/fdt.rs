@@ -0,0 +1,366 @@
+//! Minimal flattened device tree reader
+//!
+//! Just enough to find nodes and read properties from the `dtb_pa` passed to `main`,
+//! without allocation. Ref: https://devicetree-specification.readthedocs.io
+
+use core::convert::TryInto;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// Deepest node tracked for `#address-cells` and `#size-cells`
+const MAX_DEPTH: usize = 16;
+
+/// A flattened device tree blob
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: usize,
+    strings: usize,
+}
+
+impl<'a> Fdt<'a> {
+    /// Read the device tree at `addr`
+    ///
+    /// Returns `None` if there's no valid device tree header.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be readable for the whole `totalsize` in the header.
+    pub unsafe fn from_ptr(addr: usize) -> Option<Fdt<'a>> {
+        if addr == 0 || addr % 4 != 0 {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, 40);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = be32(header, 4)? as usize;
+        Fdt::new(core::slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    /// Read the device tree in `data`
+    pub fn new(data: &'a [u8]) -> Option<Fdt<'a>> {
+        if be32(data, 0)? != FDT_MAGIC || be32(data, 4)? as usize > data.len() {
+            return None;
+        }
+        Some(Fdt {
+            data,
+            structs: be32(data, 8)? as usize,
+            strings: be32(data, 12)? as usize,
+        })
+    }
+
+    /// Size of the device tree blob in bytes
+    #[inline]
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Iterate all nodes in depth-first order, starting from the root node
+    #[inline]
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            fdt: *self,
+            offset: self.structs,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH],
+        }
+    }
+
+    /// Find a node by full path like `/cpus` or `/soc/serial@10000000`
+    ///
+    /// Unit addresses can be left out if the path component has no `@`.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let components = || path.split('/').filter(|c| !c.is_empty());
+        let target = components().count();
+        let mut matched = 0;
+        for node in self.nodes() {
+            // left the subtree of the last matched component
+            if node.depth <= matched {
+                matched = node.depth.saturating_sub(1);
+            }
+            if node.depth == 0 {
+                if target == 0 {
+                    return Some(node);
+                }
+                continue;
+            }
+            if node.depth == matched + 1 && name_matches(node.name, components().nth(matched)?) {
+                matched += 1;
+                if matched == target {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+
+    /// Find the first node compatible with any of `compatibles`
+    pub fn find_compatible(&self, compatibles: &[&str]) -> Option<Node<'a>> {
+        self.nodes()
+            .find(|node| compatibles.iter().any(|c| node.is_compatible(c)))
+    }
+
+    /// Find the node with `phandle`
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes()
+            .find(|node| node.property_u32("phandle") == Some(phandle))
+    }
+
+    /// Hart id of the cpu node containing the interrupt controller with `phandle`
+    ///
+    /// Interrupt controllers refer to harts this way in `interrupts-extended`.
+    pub fn hart_of_controller(&self, phandle: u32) -> Option<usize> {
+        let mut cpu = None;
+        for node in self.nodes() {
+            if node.property_str("device_type") == Some("cpu") {
+                cpu = node.reg().next().map(|(hartid, _)| (node.depth, hartid as usize));
+            } else if let Some((depth, hartid)) = cpu {
+                if node.depth <= depth {
+                    cpu = None;
+                } else if node.property_u32("phandle") == Some(phandle) {
+                    return Some(hartid);
+                }
+            }
+        }
+        None
+    }
+
+    /// The `/chosen` node
+    #[inline]
+    pub fn chosen(&self) -> Option<Node<'a>> {
+        self.find_node("/chosen")
+    }
+
+    #[inline]
+    fn token(&self, offset: usize) -> Option<u32> {
+        be32(self.data, offset)
+    }
+
+    fn string(&self, offset: usize) -> Option<&'a str> {
+        let bytes = self.data.get(self.strings + offset..)?;
+        cstr(bytes)
+    }
+}
+
+/// A device tree node
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    // offset of the first token after node name
+    props: usize,
+    name: &'a str,
+    depth: usize,
+    // `#address-cells` and `#size-cells` of the parent node
+    cells: (u32, u32),
+}
+
+impl<'a> Node<'a> {
+    /// Node name with unit address, like `serial@10000000`
+    #[inline]
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Depth in the tree, zero for the root node
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Iterate properties of this node as `(name, value)`
+    #[inline]
+    pub fn properties(&self) -> Properties<'a> {
+        Properties {
+            fdt: self.fdt,
+            offset: self.props,
+        }
+    }
+
+    /// Raw value of property `name`
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Value of property `name` as one cell
+    #[inline]
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        be32(self.property(name)?, 0)
+    }
+
+    /// Value of property `name` as one or two cells
+    pub fn property_u64(&self, name: &str) -> Option<u64> {
+        let value = self.property(name)?;
+        match value.len() {
+            4 => be32(value, 0).map(u64::from),
+            8 => Some(u64::from_be_bytes(value.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    /// Value of property `name` as a string
+    #[inline]
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        cstr(self.property(name)?)
+    }
+
+    /// Whether the `compatible` string list has `compatible`
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        match self.property("compatible") {
+            Some(list) => list
+                .split(|&b| b == 0)
+                .any(|c| c == compatible.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Iterate `(address, size)` pairs in `reg` property
+    pub fn reg(&self) -> Reg<'a> {
+        Reg {
+            value: self.property("reg").unwrap_or(&[]),
+            address_cells: self.cells.0 as usize,
+            size_cells: self.cells.1 as usize,
+        }
+    }
+}
+
+/// Iterator over all nodes of a device tree
+pub struct Nodes<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    depth: usize,
+    cells: [(u32, u32); MAX_DEPTH],
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            let token = self.fdt.token(self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.fdt.data.get(self.offset..)?)?;
+                    self.offset = align4(self.offset + name.len() + 1);
+                    let depth = self.depth;
+                    self.depth += 1;
+                    let parent = depth
+                        .checked_sub(1)
+                        .and_then(|d| self.cells.get(d).copied())
+                        .unwrap_or((2, 1));
+                    let node = Node {
+                        fdt: self.fdt,
+                        props: self.offset,
+                        name,
+                        depth,
+                        cells: parent,
+                    };
+                    // cells of this node apply to its children
+                    let address_cells = node.property_u32("#address-cells").unwrap_or(2);
+                    let size_cells = node.property_u32("#size-cells").unwrap_or(1);
+                    if depth < MAX_DEPTH {
+                        self.cells[depth] = (address_cells, size_cells);
+                    }
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => {
+                    let len = self.fdt.token(self.offset)? as usize;
+                    self.offset = align4(self.offset + 8 + len);
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Iterator over properties of a node
+pub struct Properties<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Properties<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.fdt.token(self.offset)? {
+                FDT_PROP => {
+                    let len = self.fdt.token(self.offset + 4)? as usize;
+                    let name = self.fdt.string(self.fdt.token(self.offset + 8)? as usize)?;
+                    let start = self.offset + 12;
+                    let value = self.fdt.data.get(start..start + len)?;
+                    self.offset = align4(start + len);
+                    return Some((name, value));
+                }
+                FDT_NOP => self.offset += 4,
+                // properties come before child nodes
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Iterator over `(address, size)` pairs in `reg` property
+pub struct Reg<'a> {
+    value: &'a [u8],
+    address_cells: usize,
+    size_cells: usize,
+}
+
+impl<'a> Iterator for Reg<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let stride = (self.address_cells + self.size_cells) * 4;
+        if stride == 0 {
+            return None;
+        }
+        let address = cells(self.value, 0, self.address_cells)?;
+        let size = cells(self.value, self.address_cells, self.size_cells)?;
+        self.value = &self.value[stride..];
+        Some((address, size))
+    }
+}
+
+// Read `count` big-endian cells from cell index `index`
+fn cells(value: &[u8], index: usize, count: usize) -> Option<u64> {
+    (index..index + count).try_fold(0u64, |acc, i| {
+        be32(value, i * 4).map(|cell| acc << 32 | u64::from(cell))
+    })
+}
+
+#[inline]
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+#[inline]
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn cstr(bytes: &[u8]) -> Option<&str> {
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+fn name_matches(name: &str, component: &str) -> bool {
+    if component.contains('@') {
+        name == component
+    } else {
+        name.split('@').next() == Some(component)
+    }
+}
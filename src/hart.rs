@@ -0,0 +1,152 @@
+//! Per-hart data area and hart-local variables
+//!
+//! Each hart has a hart block: the 64-byte hart area, followed by its TLS block.
+//! `_abs_start` points `tp` to the TLS block as the TLS ABI requires, so the area is right
+//! below `tp`. The area holds scratch words for the trap entry, the trap stack, the hart id
+//! and the hart-local block: a copy of the `.hart_local` section made for each hart.
+//!
+//! `sscratch` holds the address of the area, so the trap entry finds it whatever `tp` the
+//! interrupted code has, and runs trap handlers with `tp` of the hart; the `tp` of the
+//! interrupted code is saved in [`TrapFrame`](crate::TrapFrame). Don't change `sscratch`,
+//! and keep `tp` of the hart in the kernel outside of traps to use hart-local variables.
+
+use crate::TrapFrame;
+use core::cell::UnsafeCell;
+
+// Words of the hart area from `tp - 8 words`, in sync with the assembly code:
+// t0 and t1 saved by the trap entry, the bottom of this hart's stack guard page, the top
+// of its trap stack, the hart id, its hart-local block, the trap frame being handled
+// and the kernel stack for traps from user mode, set by `__restore`
+const AREA_WORDS: usize = 8;
+const AREA_GUARD: usize = 2;
+const AREA_TRAP_STACK: usize = 3;
+const AREA_HART_ID: usize = 4;
+const AREA_LOCAL_BASE: usize = 5;
+const AREA_TRAP_FRAME: usize = 6;
+
+extern "C" {
+    // Initial values of hart-local variables, in .data
+    static _shart_local: u8;
+    static _ehart_local: u8;
+    // Hart-local blocks of all harts, one after another
+    static _shart_locals: u8;
+    // Trap stacks, laid out like hart stacks
+    static _strap_stack: u8;
+    static _trap_stack_size: u8;
+}
+
+#[inline]
+fn area() -> *mut usize {
+    let tp: *mut usize;
+    unsafe { llvm_asm!("mv $0, tp" : "=r"(tp)) };
+    tp.wrapping_sub(AREA_WORDS)
+}
+
+// Fill the hart area set up by `_abs_start`, as early as possible for panic reports,
+// and point `sscratch` to it for the trap entry
+pub(crate) unsafe fn init_area(hartid: usize) {
+    let trap_stack = &_strap_stack as *const u8 as usize
+        - hartid * (&_trap_stack_size as *const u8 as usize);
+    let area = area();
+    area.add(AREA_GUARD).write(crate::stack::bounds(hartid).0);
+    area.add(AREA_TRAP_STACK).write(trap_stack);
+    area.add(AREA_HART_ID).write(hartid);
+    area.add(AREA_LOCAL_BASE).write(0);
+    area.add(AREA_TRAP_FRAME).write(0);
+    llvm_asm!("csrw sscratch, $0" :: "r"(area) :: "volatile");
+}
+
+#[inline]
+fn local_block_size() -> usize {
+    unsafe { &_ehart_local as *const u8 as usize - &_shart_local as *const u8 as usize }
+}
+
+#[inline]
+fn local_base(hartid: usize) -> usize {
+    unsafe { &_shart_locals as *const u8 as usize + hartid * local_block_size() }
+}
+
+// Copy the hart-local blocks of all harts on the boot hart, after .data is initialized,
+// so other harts may access them before those harts start
+pub(crate) unsafe fn init_local_blocks() {
+    let start = &_shart_local as *const u8;
+    for hartid in 0..=crate::max_hart_id() {
+        core::ptr::copy_nonoverlapping(start, local_base(hartid) as *mut u8, local_block_size());
+    }
+}
+
+// Point the hart area to its hart-local block and initialize the TLS block
+pub(crate) unsafe fn init_locals(hartid: usize) {
+    let area = area();
+    area.add(AREA_LOCAL_BASE).write(local_base(hartid));
+    crate::tls::init(area.add(AREA_WORDS) as *mut u8);
+}
+
+// Trap frame being handled on current hart, null outside of traps
+#[inline]
+pub(crate) fn trap_frame() -> *mut TrapFrame {
+    unsafe { area().add(AREA_TRAP_FRAME).read() as *mut TrapFrame }
+}
+
+// Set the trap frame being handled, returns the previous one for nested traps
+#[inline]
+pub(crate) fn replace_trap_frame(trap_frame: *mut TrapFrame) -> *mut TrapFrame {
+    unsafe {
+        let slot = area().add(AREA_TRAP_FRAME);
+        let previous = slot.read();
+        slot.write(trap_frame as usize);
+        previous as *mut TrapFrame
+    }
+}
+
+/// Id of the hart running this code
+#[inline]
+pub fn hart_id() -> usize {
+    unsafe { area().add(AREA_HART_ID).read() }
+}
+
+/// A variable with a copy for each hart, see [`hart_local`](crate::hart_local)
+///
+/// Each hart accesses its own copy. Like `thread_local!`, the value may also be used
+/// by trap handlers on the same hart; disable interrupts around non-reentrant updates.
+pub struct HartLocal<T> {
+    value: UnsafeCell<T>,
+}
+
+// Harts never share a copy
+unsafe impl<T> Sync for HartLocal<T> {}
+
+impl<T> HartLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(value: T) -> Self {
+        HartLocal {
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Call `f` with the copy of current hart
+    ///
+    /// Must not be used before `main`, e.g. in `pre_init` or `_mp_hook`.
+    #[inline]
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        f(unsafe { &*self.as_ptr() })
+    }
+
+    /// Raw pointer to the copy of current hart
+    #[inline]
+    pub fn as_ptr(&'static self) -> *mut T {
+        let base = unsafe { area().add(AREA_LOCAL_BASE).read() };
+        (base + self.offset()) as *mut T
+    }
+
+    // Raw pointer to the copy of hart `hartid`, for data shared with other harts
+    #[inline]
+    pub(crate) fn as_ptr_of(&'static self, hartid: usize) -> *mut T {
+        (local_base(hartid) + self.offset()) as *mut T
+    }
+
+    #[inline]
+    fn offset(&'static self) -> usize {
+        self.value.get() as usize - unsafe { &_shart_local as *const u8 as usize }
+    }
+}
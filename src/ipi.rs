@@ -0,0 +1,187 @@
+//! Messages between harts over supervisor software interrupts
+//!
+//! Each hart has a mailbox of [`MAILBOX_SIZE`] messages, which any hart may post to without
+//! locks. [`send_ipi`] posts a message and sends an SBI IPI; the default `SupervisorSoft`
+//! handler of the receiving hart drains its mailbox, calling functions of
+//! [`Message::Call`] and passing [`Message::Data`] to the receiver set by [`set_receiver`].
+//!
+//! ```ignore
+//! fn hello(from: usize) {
+//!     println!("hart {} called by hart {}", hart::hart_id(), from);
+//! }
+//!
+//! ipi::call_on_hart(1, hello, hart::hart_id())?;
+//! ```
+//!
+//! Messages are handled once the receiving hart enables interrupts. If you define your
+//! own `SupervisorSoft` with `#[interrupt]`, call [`handle_interrupt`] from it.
+
+use crate::hart::{self, HartLocal};
+use crate::sbi::{self, HartMask, SbiError};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of pending messages for each hart
+pub const MAILBOX_SIZE: usize = 16;
+
+/// Message to another hart
+#[derive(Clone, Copy, Debug)]
+pub enum Message {
+    /// Call the function with the argument on the receiving hart
+    Call(fn(usize), usize),
+    /// Pass the word to the receiver set by [`set_receiver`]
+    Data(usize),
+}
+
+/// Error when sending a message
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpiError {
+    /// Mailbox of the hart is full; messages to harts before it are sent
+    MailboxFull(usize),
+    /// SBI failed to send the interrupt
+    Sbi(SbiError),
+}
+
+// Words of a slot: state, function (zero for data), argument or data, sender hart id.
+// The state is `2 * lap` while free and `2 * lap + 1` while full, so zeroed slots are free.
+const SLOT_STATE: usize = 0;
+const SLOT_FUNCTION: usize = 1;
+const SLOT_ARGUMENT: usize = 2;
+const SLOT_FROM: usize = 3;
+
+// Bounded multi-producer single-consumer queue; only its hart takes messages
+struct Mailbox {
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    slots: UnsafeCell<[[usize; 4]; MAILBOX_SIZE]>,
+}
+
+impl Mailbox {
+    const fn new() -> Self {
+        Mailbox {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            slots: UnsafeCell::new([[0; 4]; MAILBOX_SIZE]),
+        }
+    }
+
+    fn slot(&self, position: usize) -> (*mut usize, &AtomicUsize) {
+        let slot = unsafe { (*self.slots.get())[position % MAILBOX_SIZE].as_mut_ptr() };
+        (slot, unsafe { &*(slot.add(SLOT_STATE) as *const AtomicUsize) })
+    }
+
+    fn push(&self, words: [usize; 3]) -> bool {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+        loop {
+            let (slot, state) = self.slot(tail);
+            let lap = tail / MAILBOX_SIZE;
+            if state.load(Ordering::Acquire) != lap.wrapping_mul(2) {
+                // Full if the slot is still taken from the previous lap
+                let current = self.tail.load(Ordering::Relaxed);
+                if current == tail {
+                    return false;
+                }
+                tail = current;
+                continue;
+            }
+            match self.tail.compare_exchange_weak(
+                tail,
+                tail.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => unsafe {
+                    slot.add(SLOT_FUNCTION).write(words[0]);
+                    slot.add(SLOT_ARGUMENT).write(words[1]);
+                    slot.add(SLOT_FROM).write(words[2]);
+                    state.store(lap.wrapping_mul(2) + 1, Ordering::Release);
+                    return true;
+                },
+                Err(current) => tail = current,
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<[usize; 3]> {
+        let head = self.head.load(Ordering::Relaxed);
+        let (slot, state) = self.slot(head);
+        let lap = head / MAILBOX_SIZE;
+        if state.load(Ordering::Acquire) != lap.wrapping_mul(2) + 1 {
+            return None;
+        }
+        let words = unsafe {
+            [
+                slot.add(SLOT_FUNCTION).read(),
+                slot.add(SLOT_ARGUMENT).read(),
+                slot.add(SLOT_FROM).read(),
+            ]
+        };
+        self.head.store(head.wrapping_add(1), Ordering::Relaxed);
+        state.store(lap.wrapping_add(1).wrapping_mul(2), Ordering::Release);
+        Some(words)
+    }
+}
+
+// Shared with other harts through `HartLocal::as_ptr_of`
+unsafe impl Sync for Mailbox {}
+
+#[link_section = ".hart_local"]
+static MAILBOX: HartLocal<Mailbox> = HartLocal::new(Mailbox::new());
+
+// `fn(from, data)` receiving `Message::Data`, zero if none
+static RECEIVER: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the function called with the sender hart id and data of each [`Message::Data`]
+///
+/// Data messages arriving before it's set are dropped.
+#[inline]
+pub fn set_receiver(receiver: fn(usize, usize)) {
+    RECEIVER.store(receiver as usize, Ordering::Release);
+}
+
+/// Post `message` to the mailboxes of `harts` and interrupt them
+///
+/// Current hart may be one of `harts`. Returns once the message is posted, without waiting
+/// for it to be handled.
+pub fn send_ipi(harts: HartMask, message: Message) -> Result<(), IpiError> {
+    let words = match message {
+        Message::Call(function, argument) => [function as usize, argument, hart::hart_id()],
+        Message::Data(data) => [0, data, hart::hart_id()],
+    };
+    for hartid in (0..=crate::max_hart_id()).filter(|&hartid| harts.contains(hartid)) {
+        let mailbox = unsafe { &*MAILBOX.as_ptr_of(hartid) };
+        if !mailbox.push(words) {
+            // Still interrupt the harts already posted to
+            let _ = sbi::send_ipi(harts);
+            return Err(IpiError::MailboxFull(hartid));
+        }
+    }
+    sbi::send_ipi(harts).map_err(IpiError::Sbi)
+}
+
+/// Call `function` with `argument` on hart `hartid`
+#[inline]
+pub fn call_on_hart(hartid: usize, function: fn(usize), argument: usize) -> Result<(), IpiError> {
+    send_ipi(HartMask::single(hartid), Message::Call(function, argument))
+}
+
+/// Handle all messages in the mailbox of current hart
+///
+/// Called by the default `SupervisorSoft` handler.
+pub fn handle_interrupt() {
+    // Clear SSIP first, so messages posted while draining interrupt again
+    unsafe { llvm_asm!("csrc sip, $0" :: "r"(1 << 1) :: "volatile") };
+    let mailbox = MAILBOX.with(|mailbox| mailbox as *const Mailbox);
+    while let Some([function, argument, from]) = unsafe { (*mailbox).pop() } {
+        if function != 0 {
+            let function: fn(usize) = unsafe { core::mem::transmute(function) };
+            function(argument);
+            continue;
+        }
+        let receiver = RECEIVER.load(Ordering::Acquire);
+        if receiver != 0 {
+            let receiver: fn(usize, usize) = unsafe { core::mem::transmute(receiver) };
+            receiver(from, argument);
+        }
+    }
+}
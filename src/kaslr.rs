@@ -0,0 +1,144 @@
+//! Kernel address space layout randomization
+//!
+//! The boot hart picks a random higher half base from `/chosen/kaslr-seed` or
+//! `/chosen/rng-seed` of the device tree, maps the kernel there with an Sv39 page table
+//! built at boot, relocates the image and re-enters `_abs_start` at the new address.
+//! Without a seed the kernel keeps running where it's loaded.
+//!
+//! The image is relocated for the random base once, while choosing it. Harts re-entering
+//! `_abs_start`, and secondaries started later, find the relocations applied and skip
+//! them, so pointers in .data changed at runtime are kept.
+//!
+//! Link the kernel at its physical load address and don't use the boot page macros.
+
+use crate::fdt::Fdt;
+use crate::reloc;
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+const ENTRIES: usize = 512;
+const GIGA_SHIFT: usize = 30;
+// Sv39 root entries from 256 map the higher half
+const HIGHER_HALF_ENTRY: usize = 256;
+const SIGN_EXTEND: usize = 0xffff_ff80_0000_0000;
+// V | R | W | X | A | D
+const PTE_FLAGS: usize = 0xcf;
+const SATP_SV39: usize = 8 << 60;
+
+const UNDECIDED: usize = 0;
+const DECIDING: usize = 1;
+const RANDOMIZED: usize = 2;
+const DISABLED: usize = 3;
+
+#[repr(C, align(4096))]
+struct BootPage([usize; ENTRIES]);
+
+// Used before .bss and .data are initialized, and the page table must survive zeroing .bss;
+// copying .data onto itself at init leaves them unchanged
+#[link_section = ".data"]
+static mut BOOT_PAGE: BootPage = BootPage([0; ENTRIES]);
+#[link_section = ".data"]
+static STATE: AtomicUsize = AtomicUsize::new(UNDECIDED);
+#[link_section = ".data"]
+static SATP: AtomicUsize = AtomicUsize::new(0);
+#[link_section = ".data"]
+static OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Move to the random higher half base, choosing it on the first hart to arrive
+///
+/// Returns only if the device tree has no seed; the kernel then runs at its load address.
+pub unsafe fn randomize(hartid: usize, dtb_pa: usize) {
+    loop {
+        match STATE.compare_exchange(UNDECIDED, DECIDING, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                let state = if decide(dtb_pa) { RANDOMIZED } else { DISABLED };
+                STATE.store(state, Ordering::Release);
+            }
+            Err(DECIDING) => spin_loop_hint(),
+            Err(RANDOMIZED) => enter(hartid, dtb_pa),
+            Err(_) => return,
+        }
+    }
+}
+
+// Build the page table and relocate for the random base; returns false without a seed
+unsafe fn decide(dtb_pa: usize) -> bool {
+    // the device tree reader may need relocated pointers; this marks relocations applied,
+    // so `start_rust` won't apply them again at the random base
+    reloc::relocate();
+    let seed = match Fdt::from_ptr(dtb_pa).and_then(|fdt| seed(&fdt)) {
+        Some(seed) => seed,
+        None => return false,
+    };
+
+    let load_pa = reloc::load_base();
+    let end_pa = crate::image_end();
+    let first = load_pa >> GIGA_SHIFT;
+    let count = ((end_pa - 1) >> GIGA_SHIFT) - first + 1;
+    let slots = ENTRIES - HIGHER_HALF_ENTRY - count + 1;
+    let slot = HIGHER_HALF_ENTRY + (seed % slots as u64) as usize;
+
+    // Identity map the lower half for switching page table, the device tree and devices
+    let page = &mut BOOT_PAGE.0;
+    for (i, pte) in page.iter_mut().take(HIGHER_HALF_ENTRY).enumerate() {
+        *pte = (i << GIGA_SHIFT) >> 2 | PTE_FLAGS;
+    }
+    for i in 0..count {
+        page[slot + i] = ((first + i) << GIGA_SHIFT) >> 2 | PTE_FLAGS;
+    }
+    let va_start = SIGN_EXTEND | slot << GIGA_SHIFT | (load_pa & ((1 << GIGA_SHIFT) - 1));
+    SATP.store(SATP_SV39 | page.as_ptr() as usize >> 12, Ordering::Relaxed);
+    OFFSET.store(va_start.wrapping_sub(load_pa), Ordering::Relaxed);
+
+    // From now on pointers hold higher half addresses; only pc-relative accesses till `enter`.
+    // Only the deciding hart gets here, so this is done once too
+    let link_base = reloc::link_base();
+    reloc::apply(
+        load_pa.wrapping_sub(link_base),
+        va_start.wrapping_sub(link_base),
+    );
+    true
+}
+
+// Fold the seed bytes with FNV-1a; an all-zero seed disables randomization
+fn seed(fdt: &Fdt) -> Option<u64> {
+    let chosen = fdt.chosen()?;
+    let bytes = chosen
+        .property("kaslr-seed")
+        .or_else(|| chosen.property("rng-seed"))?;
+    if bytes.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash: u64, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    Some(hash)
+}
+
+unsafe fn enter(hartid: usize, dtb_pa: usize) -> ! {
+    extern "C" {
+        fn _kaslr_enter(hartid: usize, dtb_pa: usize, satp: usize, offset: usize) -> !;
+    }
+    let offset = OFFSET.load(Ordering::Relaxed);
+    // Like the boot page trampoline, relocate the device tree if it's mapped with the kernel
+    let dtb = if dtb_pa >> GIGA_SHIFT == reloc::load_base() >> GIGA_SHIFT {
+        dtb_pa.wrapping_add(offset)
+    } else {
+        dtb_pa
+    };
+    _kaslr_enter(hartid, dtb, SATP.load(Ordering::Relaxed), offset)
+}
+
+global_asm!(
+    "
+    .section .text
+    .globl _kaslr_enter
+# a0: hart id, a1: device tree root, a2: satp, a3: offset from physical to virtual address
+_kaslr_enter:
+    csrw    satp, a2
+    sfence.vma
+    lla     t0, _abs_start
+    add     t0, t0, a3
+    mv      a2, a3
+    jr      t0
+"
+);
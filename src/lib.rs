@@ -7,14 +7,43 @@
 
 extern crate alloc;
 
-pub use riscv_sbi_rt_macros::{boot_page_sv32, boot_page_sv39, boot_page_sv48};
-pub use riscv_sbi_rt_macros::{entry, interrupt, pre_init};
+pub use riscv_sbi_rt_macros::{boot_page_sv32, boot_page_sv39, boot_page_sv48, boot_page_sv57};
+pub use riscv_sbi_rt_macros::{
+    entry, external_interrupt, hart_local, interrupt, panic_hook, pre_init,
+};
 
 use core::alloc::Layout;
 use core::panic::PanicInfo;
 use core::sync::atomic::*;
-use riscv::register::{scause, sstatus::Sstatus, stvec};
-use riscv_sbi::println;
+use riscv::register::{satp, scause, sstatus, sstatus::Sstatus, stval, stvec};
+
+pub use dump::TrapDump;
+pub use hart::HartLocal;
+pub use sbi::sbi_features;
+
+pub mod aia;
+#[cfg(feature = "backtrace")]
+pub mod backtrace;
+pub mod console;
+pub mod disasm;
+mod dump;
+pub mod fdt;
+pub mod hart;
+pub mod ipi;
+#[cfg(all(feature = "kaslr", target_pointer_width = "64"))]
+mod kaslr;
+pub mod logger;
+#[cfg(target_pointer_width = "64")]
+pub mod paging;
+pub mod plic;
+#[cfg(feature = "pie")]
+mod reloc;
+pub mod sbi;
+pub mod stack;
+pub mod timer;
+pub mod tlb;
+pub mod tls;
+pub mod uart;
 
 // __ONCE__ is replaced in Cargo.toml file
 
@@ -46,13 +75,15 @@ extern "Rust" {
     static _sidata: u64;
 }
 
+static VA_PA_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
 /// Rust entry point (_start_rust)
 ///
 /// # Safety
 ///
 /// This function should only be called by startup assembly code
 #[export_name = "_start_rust"]
-pub unsafe extern "C" fn start_rust(hartid: usize, dtb_pa: usize) -> ! {
+pub unsafe extern "C" fn start_rust(hartid: usize, dtb_pa: usize, va_pa_offset: usize) -> ! {
     #[rustfmt::skip]
     extern "C" {
         // interrupt entry provided by assemble
@@ -71,14 +102,39 @@ pub unsafe extern "C" fn start_rust(hartid: usize, dtb_pa: usize) -> ! {
         fn main(hartid: usize, dtb_pa: usize);
     }
 
+    // Harts entered at physical address move to the random base and come back here
+    #[cfg(all(feature = "kaslr", target_pointer_width = "64"))]
+    {
+        if va_pa_offset == 0 {
+            kaslr::randomize(hartid, dtb_pa);
+        }
+    }
+
+    // The first hart relocates before touching any pointer in .data or .got, before
+    // `_mp_hook` can release other harts; harts arriving later find it done
+    #[cfg(feature = "pie")]
+    reloc::relocate();
+    hart::init_area(hartid);
+
     static READY: AtomicBool = AtomicBool::new(false);
     if _mp_hook(hartid, dtb_pa) {
         __pre_init();
 
         r0::zero_bss(&mut _sbss, &mut _ebss);
         r0::init_data(&mut _sdata, &mut _edata, &_sidata);
+        stack::init_canaries();
+        hart::init_local_blocks();
+
+        VA_PA_OFFSET.store(va_pa_offset, Ordering::Relaxed);
+        sbi::probe();
+        // The UART is reachable at the address in the device tree only without paging
+        if satp::read().mode() == satp::Mode::Bare {
+            if let Some(fdt) = fdt::Fdt::from_ptr(dtb_pa) {
+                console::init(&fdt);
+            }
+        }
 
-        riscv_sbi::log::init();
+        logger::init();
 
         READY.store(true, Ordering::Release);
     } else {
@@ -86,16 +142,20 @@ pub unsafe extern "C" fn start_rust(hartid: usize, dtb_pa: usize) -> ! {
             spin_loop_hint();
         }
     }
+    hart::init_locals(hartid);
 
     // Initialize trap hanlder
     // Use RISC-V defined Default mode, using trap entry `_start_trap_sbi`
     stvec::write(_start_trap_sbi as usize, stvec::TrapMode::Direct);
+    // Messages from other harts and PLIC interrupts arrive once interrupts are enabled
+    enable_interrupt(Interrupt::SupervisorSoft);
+    enable_interrupt(Interrupt::SupervisorExternal);
 
     // Launch main function
     main(hartid, dtb_pa);
 
     // Shutdown
-    riscv_sbi::legacy::shutdown()
+    sbi::shutdown()
 }
 
 // Ref: https://github.com/rust-embedded/riscv-rt/blob/master/asm.S
@@ -103,7 +163,13 @@ global_asm!("
     .section .init
     .globl _start
     .weak _start
+    .globl _start_secondary
+    .weak _start_secondary
 _start:
+_start_secondary:
+    /* Without a boot page, virtual and physical addresses are the same */
+    li  a2, 0
+
     .globl _abs_start
 _abs_start:
     .cfi_startproc
@@ -111,25 +177,41 @@ _abs_start:
 
     /* a0: hart id */
     /* a1: device tree root */
+    /* a2: offset from physical to virtual address of the kernel image */
+
+    /* Must be entered at the virtual address; then the pc-relative loads */
+    /* below give virtual addresses for `gp` and `sp` */
 
     /* Setup global pointer */
     .option push
     .option norelax
-    la  gp, __global_pointer$
+    lla gp, __global_pointer$
     .option pop
 
-    beqz    a0, 1f
-    /* Other harts are halted, waiting for software interrupt */
-    wfi
-    j       2f
-1:
-    /* Prepare stack for hart 0; */
-    /* stack for other harts are prepared in init code in hart 0 */
-    /* Load symbols */
-    la  sp, _sstack
-2:
+    /* Harts without a stack slot are halted */
+    lui t0, %hi(_max_hart_id)
+    addi t0, t0, %lo(_max_hart_id)
+    bgtu a0, t0, _start_abort
+
+    /* Prepare stack for each hart: */
+    /* sp = _sstack - hartid * _hart_stack_size */
+    lui t0, %hi(_hart_stack_size)
+    addi t0, t0, %lo(_hart_stack_size)
+    mul t0, t0, a0
+    lla sp, _sstack
+    sub sp, sp, t0
+
+    /* Hart block of each hart: 64-byte hart area, then the TLS block at tp */
+    /* tp = _shart_blocks + hartid * _hart_block_size + 64 */
+    lui t0, %hi(_hart_block_size)
+    addi t0, t0, %lo(_hart_block_size)
+    mul t0, t0, a0
+    lla tp, _shart_blocks
+    add tp, tp, t0
+    addi tp, tp, 64
+
     /* If entry function returns, it should abort */
-    la  ra, _start_abort
+    lla ra, _start_abort
 
     /* Jump to rust entry function */
     j   _start_rust
@@ -139,7 +221,7 @@ _abs_start:
 _start_abort:
     wfi
     j   _start_abort
-"#
+"
 );
 
 #[doc(hidden)]
@@ -161,12 +243,123 @@ pub unsafe extern "Rust" fn default_mp_hook(hartid: usize, _dtb_pa: usize) -> bo
     }
 }
 
+// by default, panic reports have nothing more.
+// if you need to add to them, define a function with `#[panic_hook]`.
+#[doc(hidden)]
+#[no_mangle]
+#[rustfmt::skip]
+pub unsafe extern "Rust" fn default_panic_hook(_info: &PanicInfo) {}
+
+/// What to do after printing a panic message
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PanicPolicy {
+    /// Halt the panicking hart, for inspecting with a debugger
+    Halt = 0,
+    /// Shut down the system with reason `system_failure`
+    Shutdown = 1,
+    /// Cold reboot the system with reason `system_failure`
+    Reboot = 2,
+}
+
+static PANIC_POLICY: AtomicUsize = AtomicUsize::new(PanicPolicy::Halt as usize);
+
+/// Set what to do on panic, `PanicPolicy::Halt` by default
+///
+/// Use `PanicPolicy::Shutdown` in tests under QEMU, so a panic exits QEMU with
+/// a failure status instead of hanging.
+///
+/// Before that, the panicking hart asks other harts to halt with an IPI and waits for them
+/// up to 100 ms. Harts running with interrupts disabled don't take the IPI; they're reported
+/// as not stopped and keep running until the reset.
+#[inline]
+pub fn set_panic_policy(policy: PanicPolicy) {
+    PANIC_POLICY.store(policy as usize, Ordering::Relaxed);
+}
+
+// Hart id + 1 of the first panicking hart, zero if none
+static PANICKED: AtomicUsize = AtomicUsize::new(0);
+
+// Set by a hart halting for the panic of another hart
+#[link_section = ".hart_local"]
+static STOPPED: HartLocal<AtomicBool> = HartLocal::new(AtomicBool::new(false));
+
+// How long the panicking hart waits for other harts to stop
+const STOP_TIMEOUT: core::time::Duration = core::time::Duration::from_millis(100);
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    extern "Rust" {
+        fn __panic_hook(info: &PanicInfo);
+    }
+
+    let hartid = hart::hart_id();
+    match PANICKED.compare_exchange(0, hartid + 1, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {}
+        // Panicked again while reporting
+        Err(first) if first == hartid + 1 => {
+            println!("hart {} nested {}", hartid, info);
+            halt();
+        }
+        // Another hart is reporting its panic
+        Err(_) => stop(),
+    }
+
+    // Don't wait for a hart that stopped while printing
+    console::force_lock();
+    println!("hart {} {}", hartid, info);
+    let trap_frame = hart::trap_frame();
+    if !trap_frame.is_null() {
+        println!(
+            "in trap: sepc={:#x} scause={:#x} stval={:#x}",
+            unsafe { (*trap_frame).sepc },
+            scause::read().bits(),
+            stval::read()
+        );
+    }
+    #[cfg(feature = "backtrace")]
+    backtrace::print();
+    unsafe { __panic_hook(info) };
+
+    // Other harts halt at their next trap
+    let _ = sbi::send_ipi(sbi::HartMask::all());
+    wait_for_stop(hartid);
+
+    let reset_type = match PANIC_POLICY.load(Ordering::Relaxed) {
+        1 => sbi::ResetType::Shutdown,
+        2 => sbi::ResetType::ColdReboot,
+        _ => halt(),
+    };
+    sbi::system_reset(reset_type, sbi::ResetReason::SystemFailure);
     halt();
 }
 
+// Halt current hart for the panic of another hart, telling it this hart stopped
+fn stop() -> ! {
+    unsafe { &*STOPPED.as_ptr_of(hart::hart_id()) }.store(true, Ordering::Release);
+    halt()
+}
+
+// Wait up to `STOP_TIMEOUT` for other harts to stop: harts started according to HSM,
+// or all harts up to `max_hart_id` without HSM. A hart running with interrupts disabled
+// doesn't take the IPI and keeps running; it's reported, and the panic policy goes on.
+fn wait_for_stop(hartid: usize) {
+    let deadline = timer::now().saturating_add(timer::duration_to_ticks(STOP_TIMEOUT));
+    for other in (0..=max_hart_id()).filter(|&other| other != hartid) {
+        match sbi::hart_get_status(other) {
+            Ok(sbi::HartStatus::Stopped) => continue,
+            Err(sbi::SbiError::NotSupported) | Ok(_) => {}
+            Err(_) => continue,
+        }
+        let stopped = unsafe { &*STOPPED.as_ptr_of(other) };
+        while !stopped.load(Ordering::Acquire) && timer::now() < deadline {
+            spin_loop_hint();
+        }
+        if !stopped.load(Ordering::Acquire) {
+            println!("hart {} didn't stop", other);
+        }
+    }
+}
+
 #[no_mangle]
 extern "C" fn abort() -> ! {
     panic!("abort!");
@@ -185,6 +378,20 @@ fn halt() -> ! {
     }
 }
 
+/// Run `f` with supervisor interrupts disabled on current hart
+///
+/// Interrupts are enabled again afterwards if they were enabled before.
+#[inline]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie() };
+    let result = f();
+    if enabled {
+        unsafe { sstatus::set_sie() };
+    }
+    result
+}
+
 /// Get the maximum hart id for this runtime
 #[inline]
 pub fn max_hart_id() -> usize {
@@ -194,6 +401,27 @@ pub fn max_hart_id() -> usize {
     unsafe { &_max_hart_id as *const _ as usize }
 }
 
+/// Get the offset from physical to virtual address of the kernel image
+///
+/// This is zero without a boot page, or when the boot page maps the kernel to itself.
+#[inline]
+pub fn va_pa_offset() -> usize {
+    VA_PA_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Get the physical address where secondary harts should start
+///
+/// Pass this to SBI HSM `hart_start`; the started hart goes through the same boot page
+/// trampoline as the boot hart. As the trampoline relocates `a1` like the device tree root,
+/// use the physical device tree address as `opaque` to keep `dtb_pa` meaningful in `main`.
+#[inline]
+pub fn secondary_entry() -> usize {
+    extern "C" {
+        fn _start_secondary();
+    }
+    (_start_secondary as usize).wrapping_sub(va_pa_offset())
+}
+
 /// Returns a pointer to the start of the heap
 ///
 /// The returned pointer is guaranteed to be 4K-byte aligned for frames and paging.
@@ -206,30 +434,82 @@ pub fn heap_start() -> *mut usize {
     unsafe { &mut _sheap }
 }
 
+/// Returns a pointer to the start of the frame region
+///
+/// The frame region is 4K-byte aligned and `_frame_size` bytes long, for page tables
+/// and other physical frames, see [`paging::BumpFrameAllocator`].
+#[inline]
+pub fn frame_start() -> *mut usize {
+    extern "C" {
+        static mut _sframe: usize;
+    }
+
+    unsafe { &mut _sframe }
+}
+
+/// Returns a pointer to the end of the frame region
+#[inline]
+pub fn frame_end() -> *mut usize {
+    extern "C" {
+        static mut _eframe: usize;
+    }
+
+    unsafe { &mut _eframe }
+}
+
+// End of the memory used by the kernel image, the highest end of the sections after .bss
+pub(crate) fn image_end() -> usize {
+    extern "C" {
+        static _ehart_blocks: u8;
+        static _eheap: u8;
+        static _strap_stack: u8;
+        static _eframe: u8;
+    }
+    unsafe { [&_ehart_blocks, &_eheap, &_strap_stack, &_eframe] }
+        .iter()
+        .map(|&symbol| symbol as *const u8 as usize)
+        .max()
+        .unwrap()
+}
+
 // supervisor interrupt handler
 
 #[cfg(target_pointer_width = "64")]
 global_asm!(
     "
     .equ REGBYTES, 8
+    .equ STACK_CANARY, 0x57acc0de57acc0de
     .macro SAVE reg, offset
         sd  \\reg, \\offset*REGBYTES(sp)
     .endm
     .macro LOAD reg, offset
         ld  \\reg, \\offset*REGBYTES(sp)
     .endm
+    .macro STORE_TO reg, offset, base
+        sd  \\reg, \\offset*REGBYTES(\\base)
+    .endm
+    .macro LOAD_FROM reg, offset, base
+        ld  \\reg, \\offset*REGBYTES(\\base)
+    .endm
 "
 );
 #[cfg(target_pointer_width = "32")]
 global_asm!(
     "
     .equ REGBYTES, 4
+    .equ STACK_CANARY, 0x57acc0de
     .macro SAVE reg, offset
         sw  \\reg, \\offset*REGBYTES(sp)
     .endm
     .macro LOAD reg, offset
         lw  \\reg, \\offset*REGBYTES(sp)
     .endm
+    .macro STORE_TO reg, offset, base
+        sw  \\reg, \\offset*REGBYTES(\\base)
+    .endm
+    .macro LOAD_FROM reg, offset, base
+        lw  \\reg, \\offset*REGBYTES(\\base)
+    .endm
 "
 );
 
@@ -244,29 +524,80 @@ global_asm!(
 _start_trap_sbi:
 # 涉及到用户线程时，保存 Context 就必须使用内核栈
 # 否则如果用户线程的栈发生缺页异常，将无法保存 Context
-# 因此，我们使用 sscratch 寄存器：
-# 处于用户线程时，保存内核栈地址；处于内核线程时，保存 0
+# sscratch holds the hart area of this hart, see hart.rs, so it's found whatever `tp` is;
+# the kernel stack for traps from user mode is kept there by `__restore`
 
 # csrrw rd, csr, rs1：csr 的值写入 rd；同时 rs1 的值写入 csr
     csrrw   sp, sscratch, sp
-    bnez    sp, _from_user
-_from_kernel:
-    csrr    sp, sscratch
+    # sp: hart area; sscratch: interrupted sp. Keep t0 and t1 in the hart area
+    STORE_TO t0, 0, sp
+    STORE_TO t1, 1, sp
+    csrr    t0, sstatus
+    andi    t0, t0, 1 << 8
+    bnez    t0, _from_kernel
 _from_user:
-    # 此时 sscratch：原先的 sp；sp：内核栈地址
-    # 在内核栈开辟 Context 的空间
-    addi    sp, sp, -34*8
+    LOAD_FROM t0, 7, sp
+    j       _save_context
+_from_kernel:
+    # Don't save the context on an overflowed stack, but on the trap stack of this hart
+    lla     t0, _stack_guarded
+    lbu     t0, 0(t0)
+    bnez    t0, 3f
+    # Without guard pages, the interrupted code overflowed the stack if the canary at its
+    # bottom is broken
+    LOAD_FROM t0, 2, sp
+    LOAD_FROM t0, 0, t0
+    li      t1, STACK_CANARY
+    beq     t0, t1, 4f
+    # Unless already on the trap stack, reporting the overflow
+    LOAD_FROM t1, 3, sp
+    csrr    t0, sscratch
+    sub     t1, t1, t0
+    lui     t0, %hi(_trap_stack_size)
+    addi    t0, t0, %lo(_trap_stack_size)
+    bgeu    t1, t0, 2f
+4:
+    csrr    t0, sscratch
+    j       _save_context
+3:
+    csrr    t0, sscratch
+    # With guard pages, a page fault in the guard page. Unmapped guard pages cause page
+    # faults (12, 13 and 15); `stval` of other traps may be zero or instruction bits
+    csrr    t1, scause
+    addi    t1, t1, -12
+    beqz    t1, 1f
+    addi    t1, t1, -1
+    beqz    t1, 1f
+    addi    t1, t1, -2
+    bnez    t1, _save_context
+1:
+    csrr    t0, stval
+    LOAD_FROM t1, 2, sp
+    sub     t0, t0, t1
+    li      t1, 4096
+    bltu    t0, t1, 2f
+    csrr    t0, sscratch
+    j       _save_context
+2:
+    LOAD_FROM t0, 3, sp
+_save_context:
+    # t0: stack to save the context on; sp: hart area, kept in t1 from now on
+    mv      t1, sp
+    # 在栈上开辟 Context 的空间
+    addi    sp, t0, -34*8
 
     # 保存通用寄存器，除了 x0（固定为 0）
     SAVE    x1, 1
     # 将原来的 sp（即 x2）保存
-    # 同时 sscratch 写 0，因为即将进入*内核线程*的中断处理流程
-    csrrw   x1, sscratch, x0
+    # 同时 sscratch 写回 hart area，供下一次中断使用
+    csrrw   x1, sscratch, t1
     SAVE    x1, 2
     SAVE    x3, 3
     SAVE    x4, 4
-    SAVE    x5, 5
-    SAVE    x6, 6
+    LOAD_FROM x1, 0, t1
+    SAVE    x1, 5
+    LOAD_FROM x1, 1, t1
+    SAVE    x1, 6
     SAVE    x7, 7
     SAVE    x8, 8
     SAVE    x9, 9
@@ -292,6 +623,11 @@ _from_user:
     SAVE    x29, 29
     SAVE    x30, 30
     SAVE    x31, 31
+    SAVE    x0, 0
+
+    # Handle the trap with `tp` of this hart, right above the hart area;
+    # `tp` of the interrupted code is restored by `__restore`
+    addi    tp, t1, 8*REGBYTES
 
     # 取出 CSR 并保存
     csrr    t0, sstatus
@@ -315,16 +651,17 @@ __restore:
     # 思考：如果恢复的是用户线程，此时的 sstatus 是用户态还是内核态
     csrw    sstatus, t0
     csrw    sepc, t1
-    # 根据即将恢复的线程属于用户还是内核，恢复 sscratch
+    # 根据即将恢复的线程属于用户还是内核，决定是否记录内核栈
     # 检查 sstatus 上的 SPP 标记
     andi    t0, t0, 1 << 8
     bnez    t0, _to_kernel
 _to_user:
-    # 将要进入用户态，需要将内核栈地址写入 sscratch
+    # 将要进入用户态，需要将内核栈地址写入 sscratch 指向的 hart area
     addi    t0, sp, 34*8
-    csrw    sscratch, t0
+    csrr    t1, sscratch
+    STORE_TO t0, 7, t1
 _to_kernel:
-    # 如果要进入内核态，sscratch 保持为 0 不变
+    # sscratch 始终保存 hart area，不需要改变
 
     # 恢复通用寄存器
     LOAD    x1, 1
@@ -409,14 +746,48 @@ pub struct TrapFrame {
 #[no_mangle]
 #[allow(unused_variables, non_snake_case)]
 pub fn DefaultExceptionHandler(trap_frame: &TrapFrame) -> ! {
-    panic!("Default exception handler!");
+    // The panic handler unwinds from its own frame, which misses the faulting function
+    #[cfg(feature = "backtrace")]
+    backtrace::print_trap(trap_frame);
+    panic!("unhandled exception\n{}", trap_frame.dump());
 }
 
 #[doc(hidden)]
 #[no_mangle]
 #[allow(unused_variables, non_snake_case)]
 pub fn DefaultInterruptHandler() {
-    panic!("Default interrupt handler!");
+    match unsafe { hart::trap_frame().as_ref() } {
+        Some(trap_frame) => panic!("unhandled interrupt\n{}", trap_frame.dump()),
+        None => panic!("unhandled interrupt, scause={:#x}", scause::read().bits()),
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn DefaultSupervisorSoft(trap_frame: &mut TrapFrame) -> *mut TrapFrame {
+    ipi::handle_interrupt();
+    trap_frame
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn DefaultSupervisorExternal(trap_frame: &mut TrapFrame) -> *mut TrapFrame {
+    if aia::is_active() {
+        aia::handle_interrupt();
+    } else {
+        plic::handle_interrupt();
+    }
+    trap_frame
+}
+
+#[doc(hidden)]
+#[no_mangle]
+#[allow(non_snake_case)]
+pub fn DefaultSupervisorTimer(trap_frame: &mut TrapFrame) -> *mut TrapFrame {
+    timer::handle_interrupt();
+    trap_frame
 }
 
 /// Trap entry point rust (_start_trap_rust)
@@ -430,12 +801,33 @@ pub fn DefaultInterruptHandler() {
 /// This function should only be called by trap initializer assembly code.
 #[export_name = "_start_trap_rust"]
 pub unsafe fn start_trap_rust(trap_frame: *mut TrapFrame) -> *mut TrapFrame {
+    // Another hart panicked and asks everyone to stop
+    let panicked = PANICKED.load(Ordering::Relaxed);
+    if panicked != 0 && panicked != hart::hart_id() + 1 {
+        stop();
+    }
+
+    let previous = hart::replace_trap_frame(trap_frame);
+    let trap_frame = handle_trap(trap_frame);
+    hart::replace_trap_frame(previous);
+    trap_frame
+}
+
+unsafe fn handle_trap(trap_frame: *mut TrapFrame) -> *mut TrapFrame {
     extern "Rust" {
         fn ExceptionHandler(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
     }
 
     let scause = scause::read();
+    if stack::is_overflowed() {
+        panic!("stack overflow on hart {}", hart::hart_id());
+    }
     if scause.is_exception() {
+        if stack::is_guarded() && is_page_fault(scause.code()) {
+            if let Some(hartid) = stack::guard_of(stval::read()) {
+                panic!("stack overflow on hart {}", hartid);
+            }
+        }
         ExceptionHandler(&mut *trap_frame)
     } else {
         let code = scause.code();
@@ -450,21 +842,202 @@ pub unsafe fn start_trap_rust(trap_frame: *mut TrapFrame) -> *mut TrapFrame {
     }
 }
 
-// Interrupts; doc hidden, for checking `#[interrupt]` name only
+// Instruction, load and store/AMO page faults
+#[inline]
+fn is_page_fault(code: usize) -> bool {
+    matches!(code, 12 | 13 | 15)
+}
+
+// Interrupts; doc hidden module, for checking `#[interrupt]` name
 #[doc(hidden)]
 pub mod trap {
+    /// Supervisor-visible local interrupts, with their `scause` codes
+    ///
+    /// Local interrupts 16 and above are defined by AIA; those from 32 only exist on RV64.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq)]
     pub enum Interrupt {
-        UserSoft,
-        SupervisorSoft,
-        UserTimer,
-        SupervisorTimer,
-        UserExternal,
-        SupervisorExternal,
+        /// User software interrupt
+        UserSoft = 0,
+        /// Supervisor software interrupt
+        SupervisorSoft = 1,
+        /// User timer interrupt
+        UserTimer = 4,
+        /// Supervisor timer interrupt
+        SupervisorTimer = 5,
+        /// User external interrupt
+        UserExternal = 8,
+        /// Supervisor external interrupt
+        SupervisorExternal = 9,
+        /// Local counter overflow interrupt of Sscofpmf
+        CounterOverflow = 13,
+        /// Local interrupt 16
+        Local16 = 16,
+        /// Local interrupt 17
+        Local17 = 17,
+        /// Local interrupt 18
+        Local18 = 18,
+        /// Local interrupt 19
+        Local19 = 19,
+        /// Local interrupt 20
+        Local20 = 20,
+        /// Local interrupt 21
+        Local21 = 21,
+        /// Local interrupt 22
+        Local22 = 22,
+        /// Local interrupt 23
+        Local23 = 23,
+        /// Local interrupt 24
+        Local24 = 24,
+        /// Local interrupt 25
+        Local25 = 25,
+        /// Local interrupt 26
+        Local26 = 26,
+        /// Local interrupt 27
+        Local27 = 27,
+        /// Local interrupt 28
+        Local28 = 28,
+        /// Local interrupt 29
+        Local29 = 29,
+        /// Local interrupt 30
+        Local30 = 30,
+        /// Local interrupt 31
+        Local31 = 31,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 32
+        Local32 = 32,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 33
+        Local33 = 33,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 34
+        Local34 = 34,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 35
+        Local35 = 35,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 36
+        Local36 = 36,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 37
+        Local37 = 37,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 38
+        Local38 = 38,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 39
+        Local39 = 39,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 40
+        Local40 = 40,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 41
+        Local41 = 41,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 42
+        Local42 = 42,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 43
+        Local43 = 43,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 44
+        Local44 = 44,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 45
+        Local45 = 45,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 46
+        Local46 = 46,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 47
+        Local47 = 47,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 48
+        Local48 = 48,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 49
+        Local49 = 49,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 50
+        Local50 = 50,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 51
+        Local51 = 51,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 52
+        Local52 = 52,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 53
+        Local53 = 53,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 54
+        Local54 = 54,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 55
+        Local55 = 55,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 56
+        Local56 = 56,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 57
+        Local57 = 57,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 58
+        Local58 = 58,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 59
+        Local59 = 59,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 60
+        Local60 = 60,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 61
+        Local61 = 61,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 62
+        Local62 = 62,
+        #[cfg(target_pointer_width = "64")]
+        /// Local interrupt 63
+        Local63 = 63,
+    }
+
+    impl Interrupt {
+        /// Interrupt code in `scause`, and bit in `sie` and `sip`
+        #[inline]
+        pub fn code(self) -> usize {
+            self as usize
+        }
     }
 
     pub use self::Interrupt as interrupt;
 }
 
+pub use trap::Interrupt;
+
+/// Number of interrupt codes handled by `#[interrupt]` handlers on this target
+pub const INTERRUPT_COUNT: usize = core::mem::size_of::<usize>() * 8;
+
+/// Enable `interrupt` in `sie` of current hart
+///
+/// Interrupts are taken only if also enabled globally with `sstatus.SIE`.
+#[inline]
+pub fn enable_interrupt(interrupt: Interrupt) {
+    unsafe { llvm_asm!("csrs sie, $0" :: "r"(1usize << interrupt.code()) :: "volatile") };
+}
+
+/// Disable `interrupt` in `sie` of current hart
+#[inline]
+pub fn disable_interrupt(interrupt: Interrupt) {
+    unsafe { llvm_asm!("csrc sie, $0" :: "r"(1usize << interrupt.code()) :: "volatile") };
+}
+
+/// Whether `interrupt` is pending in `sip` of current hart
+#[inline]
+pub fn is_interrupt_pending(interrupt: Interrupt) -> bool {
+    let sip: usize;
+    unsafe { llvm_asm!("csrr $0, sip" : "=r"(sip) ::: "volatile") };
+    sip & (1 << interrupt.code()) != 0
+}
+
 #[doc(hidden)]
 pub union Vector {
     handler: unsafe fn(trap_frame: &mut TrapFrame) -> *mut TrapFrame,
@@ -474,8 +1047,10 @@ pub union Vector {
 
 #[doc(hidden)]
 #[no_mangle]
-pub static __INTERRUPTS: [Vector; 12] = [
-    Vector { handler: UserSoft },
+pub static __INTERRUPTS: [Vector; INTERRUPT_COUNT] = [
+    Vector {
+        handler: UserSoft,
+    },
     Vector {
         handler: SupervisorSoft,
     },
@@ -485,7 +1060,9 @@ pub static __INTERRUPTS: [Vector; 12] = [
     Vector {
         invalid: DefaultHandler,
     },
-    Vector { handler: UserTimer },
+    Vector {
+        handler: UserTimer,
+    },
     Vector {
         handler: SupervisorTimer,
     },
@@ -507,6 +1084,98 @@ pub static __INTERRUPTS: [Vector; 12] = [
     Vector {
         invalid: DefaultHandler,
     },
+    Vector {
+        reserved: DefaultHandler,
+    },
+    Vector {
+        handler: CounterOverflow,
+    },
+    Vector {
+        reserved: DefaultHandler,
+    },
+    Vector {
+        reserved: DefaultHandler,
+    },
+    Vector { handler: Local16 },
+    Vector { handler: Local17 },
+    Vector { handler: Local18 },
+    Vector { handler: Local19 },
+    Vector { handler: Local20 },
+    Vector { handler: Local21 },
+    Vector { handler: Local22 },
+    Vector { handler: Local23 },
+    Vector { handler: Local24 },
+    Vector { handler: Local25 },
+    Vector { handler: Local26 },
+    Vector { handler: Local27 },
+    Vector { handler: Local28 },
+    Vector { handler: Local29 },
+    Vector { handler: Local30 },
+    Vector { handler: Local31 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local32 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local33 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local34 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local35 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local36 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local37 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local38 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local39 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local40 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local41 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local42 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local43 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local44 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local45 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local46 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local47 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local48 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local49 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local50 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local51 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local52 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local53 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local54 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local55 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local56 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local57 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local58 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local59 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local60 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local61 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local62 },
+    #[cfg(target_pointer_width = "64")]
+    Vector { handler: Local63 },
 ];
 
 extern "Rust" {
@@ -516,6 +1185,59 @@ extern "Rust" {
     fn SupervisorTimer(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
     fn UserExternal(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
     fn SupervisorExternal(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn CounterOverflow(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local16(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local17(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local18(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local19(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local20(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local21(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local22(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local23(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local24(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local25(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local26(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local27(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local28(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local29(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local30(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local31(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
 
     fn DefaultHandler();
 }
+
+#[cfg(target_pointer_width = "64")]
+extern "Rust" {
+    fn Local32(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local33(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local34(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local35(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local36(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local37(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local38(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local39(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local40(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local41(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local42(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local43(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local44(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local45(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local46(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local47(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local48(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local49(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local50(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local51(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local52(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local53(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local54(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local55(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local56(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local57(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local58(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local59(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local60(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local61(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local62(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+    fn Local63(trap_frame: &mut TrapFrame) -> *mut TrapFrame;
+}
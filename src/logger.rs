@@ -0,0 +1,115 @@
+//! Logger of the `log` crate on the console, installed at boot
+//!
+//! Each record is one line with the uptime and hart id, printed while holding the console
+//! lock, so lines of different harts don't interleave:
+//!
+//! ```text
+//! [    1.002345] hart 1  INFO booted
+//! ```
+//!
+//! Warnings of the runtime's external interrupt handlers are formatted first and written
+//! at once instead, so they stay whole lines even if the handler interrupts a print.
+//!
+//! The level is `LOG` in the environment at build time, like `LOG=debug`, or `info` if
+//! it's not set. Change it with [`set_level`].
+
+use crate::{console, hart, println, timer};
+use core::fmt::{self, Write};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+// Longest line of `warn_in_interrupt` with its newline, longer ones are cut
+const LINE_SIZE: usize = 128;
+
+struct Logger;
+
+static LOGGER: Logger = Logger;
+
+impl Log for Logger {
+    #[inline]
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let uptime = timer::uptime();
+        println!(
+            "[{:>5}.{:06}] hart {} {:>5} {}",
+            uptime.as_secs(),
+            uptime.subsec_micros(),
+            hart::hart_id(),
+            record.level(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+// Install the logger, on the boot hart
+pub(crate) fn init() {
+    let level = match option_env!("LOG") {
+        Some("off") => LevelFilter::Off,
+        Some("error") => LevelFilter::Error,
+        Some("warn") => LevelFilter::Warn,
+        Some("debug") => LevelFilter::Debug,
+        Some("trace") => LevelFilter::Trace,
+        _ => LevelFilter::Info,
+    };
+    // Fails only if another logger is set, which is then kept
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+// Log a warning from an interrupt handler, like `log::warn!` but as one write without the
+// console lock. The lock is reentrant, so a record of a handler interrupting a print of
+// the same hart would go through the logger in pieces between the pieces of that print.
+pub(crate) fn warn_in_interrupt(args: fmt::Arguments) {
+    if Level::Warn > log::max_level() {
+        return;
+    }
+    let mut line = Line {
+        bytes: [0; LINE_SIZE],
+        len: 0,
+    };
+    let uptime = timer::uptime();
+    let _ = write!(
+        line,
+        "[{:>5}.{:06}] hart {} {:>5} {}",
+        uptime.as_secs(),
+        uptime.subsec_micros(),
+        hart::hart_id(),
+        Level::Warn,
+        args
+    );
+    line.bytes[line.len] = b'\n';
+    line.len += 1;
+    console::write_str(unsafe { core::str::from_utf8_unchecked(&line.bytes[..line.len]) });
+}
+
+struct Line {
+    bytes: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Cut at a char boundary, so the line stays UTF-8, and keep room for the newline
+        let mut len = s.len().min(LINE_SIZE - 1 - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// Log records up to `level` from now on
+#[inline]
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
@@ -0,0 +1,429 @@
+//! Runtime page tables, in the same format as the boot page macros
+//!
+//! After boot, adopt the boot page with [`PageTable::from_boot_page`] to modify mappings
+//! while keeping the ones set up by `boot_page_sv39!` (or `sv48`, `sv57`) valid.
+//!
+//! ```ignore
+//! let mut frames = BumpFrameAllocator::from_frame_region();
+//! let mut page_table = unsafe { PageTable::<Sv39>::from_boot_page() };
+//! page_table.map(0xffffffff_c0000000, 0x1000_0000, PageSize::Size4K, Flags::READABLE | Flags::WRITABLE, &mut frames)?;
+//! ```
+//!
+//! Page tables and frames are accessed at `physical address + phys_offset`,
+//! so every frame in use must be mapped by such linear mapping, e.g. inside the kernel image.
+//! Updating a page table does not flush the TLB; it's up to the caller.
+
+use core::marker::PhantomData;
+
+/// Paging mode of a page table
+pub trait Mode {
+    /// Number of page table levels
+    const LEVELS: usize;
+    /// `MODE` field in `satp` register
+    const SATP_MODE: usize;
+}
+
+/// Page-based 39-bit virtual addressing
+pub enum Sv39 {}
+
+/// Page-based 48-bit virtual addressing
+pub enum Sv48 {}
+
+/// Page-based 57-bit virtual addressing
+pub enum Sv57 {}
+
+impl Mode for Sv39 {
+    const LEVELS: usize = 3;
+    const SATP_MODE: usize = 8;
+}
+
+impl Mode for Sv48 {
+    const LEVELS: usize = 4;
+    const SATP_MODE: usize = 9;
+}
+
+impl Mode for Sv57 {
+    const LEVELS: usize = 5;
+    const SATP_MODE: usize = 10;
+}
+
+bitflags::bitflags! {
+    /// Page table entry flags
+    #[derive(Default)]
+    pub struct Flags: usize {
+        /// Entry is valid
+        const VALID =       1 << 0;
+        /// Page is readable
+        const READABLE =    1 << 1;
+        /// Page is writable
+        const WRITABLE =    1 << 2;
+        /// Page is executable
+        const EXECUTABLE =  1 << 3;
+        /// Page is accessible in user mode
+        const USER =        1 << 4;
+        /// Mapping exists in all address spaces
+        const GLOBAL =      1 << 5;
+        /// Page has been accessed
+        const ACCESSED =    1 << 6;
+        /// Page has been written
+        const DIRTY =       1 << 7;
+    }
+}
+
+/// Size of a leaf page
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PageSize {
+    /// 4 KiB page
+    Size4K,
+    /// 2 MiB mega page
+    Size2M,
+    /// 1 GiB giga page
+    Size1G,
+}
+
+impl PageSize {
+    /// Size of this page in bytes
+    #[inline]
+    pub const fn bytes(self) -> usize {
+        1 << (PAGE_SHIFT + LEVEL_BITS * self.level())
+    }
+
+    #[inline]
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2,
+        }
+    }
+
+    #[inline]
+    fn from_level(level: usize) -> Option<Self> {
+        match level {
+            0 => Some(PageSize::Size4K),
+            1 => Some(PageSize::Size2M),
+            2 => Some(PageSize::Size1G),
+            _ => None,
+        }
+    }
+}
+
+/// Allocator of 4K physical frames for page tables
+pub trait FrameAllocator {
+    /// Allocate a frame, returns its physical address
+    fn alloc(&mut self) -> Option<usize>;
+
+    /// Free a frame previously allocated by this allocator
+    fn dealloc(&mut self, pa: usize);
+}
+
+/// Error when mapping a page
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MapError {
+    /// Virtual or physical address is not aligned to the page size
+    Misaligned,
+    /// Virtual address is not sign extended for this paging mode
+    NonCanonical,
+    /// Virtual address is already mapped, maybe by a bigger page
+    AlreadyMapped,
+    /// Frame allocator returned no frame for a page table
+    OutOfFrames,
+}
+
+/// Error when unmapping a page
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnmapError {
+    /// Virtual address is not mapped
+    NotMapped,
+    /// Virtual address is mapped by a page bigger than 1G, e.g. from the Sv48 boot page
+    UnsupportedPageSize,
+    /// Frame allocator returned no frame for a page table when splitting a page
+    OutOfFrames,
+}
+
+const PAGE_SHIFT: usize = 12;
+const LEVEL_BITS: usize = 9;
+const ENTRIES: usize = 1 << LEVEL_BITS;
+const PPN_MASK: usize = (1 << 44) - 1;
+
+/// Page table of paging mode `M`
+pub struct PageTable<M: Mode> {
+    root: usize,
+    phys_offset: usize,
+    mode: PhantomData<M>,
+}
+
+impl<M: Mode> PageTable<M> {
+    /// Create an empty page table with a root frame from `frames`
+    ///
+    /// Frames are accessed at virtual address `pa + phys_offset`.
+    pub fn new(frames: &mut impl FrameAllocator, phys_offset: usize) -> Result<Self, MapError> {
+        let root = frames.alloc().ok_or(MapError::OutOfFrames)?;
+        unsafe { table(root, phys_offset) }.iter_mut().for_each(|pte| *pte = 0);
+        Ok(unsafe { Self::from_root(root, phys_offset) })
+    }
+
+    /// Use an existing page table with root frame at physical address `root`
+    ///
+    /// # Safety
+    ///
+    /// The root and all page tables under it must be valid for mode `M`,
+    /// and accessible at virtual address `pa + phys_offset`.
+    pub unsafe fn from_root(root: usize, phys_offset: usize) -> Self {
+        PageTable {
+            root,
+            phys_offset,
+            mode: PhantomData,
+        }
+    }
+
+    /// Adopt the `_boot_page` as the root of this page table
+    ///
+    /// Mappings from the boot page macro stay valid; new page tables are accessed through
+    /// the same offset as the kernel image, see [`va_pa_offset`](crate::va_pa_offset).
+    ///
+    /// # Safety
+    ///
+    /// `M` must match the boot page macro in use, and only one `PageTable` may own the
+    /// boot page at a time.
+    pub unsafe fn from_boot_page() -> Self {
+        extern "C" {
+            static mut _boot_page: [usize; ENTRIES];
+        }
+        let phys_offset = crate::va_pa_offset();
+        let root = (&_boot_page as *const _ as usize).wrapping_sub(phys_offset);
+        Self::from_root(root, phys_offset)
+    }
+
+    /// Physical address of the root page table
+    #[inline]
+    pub fn root_pa(&self) -> usize {
+        self.root
+    }
+
+    /// Value of `satp` register to use this page table with address space `asid`
+    #[inline]
+    pub fn satp(&self, asid: usize) -> usize {
+        M::SATP_MODE << 60 | (asid & 0xffff) << 44 | self.root >> PAGE_SHIFT
+    }
+
+    /// Switch to this page table with address space `asid` on current hart
+    ///
+    /// # Safety
+    ///
+    /// The running code, stack and data must stay mapped in this page table.
+    pub unsafe fn activate(&self, asid: usize) {
+        llvm_asm!("csrw satp, $0; sfence.vma" :: "r"(self.satp(asid)) : "memory" : "volatile");
+    }
+
+    /// Map a page of `size` from `va` to `pa`
+    ///
+    /// Missing page tables are allocated from `frames`.
+    pub fn map(
+        &mut self,
+        va: usize,
+        pa: usize,
+        size: PageSize,
+        flags: Flags,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), MapError> {
+        if va % size.bytes() != 0 || pa % size.bytes() != 0 {
+            return Err(MapError::Misaligned);
+        }
+        if !is_canonical::<M>(va) {
+            return Err(MapError::NonCanonical);
+        }
+        let mut table_pa = self.root;
+        for level in (size.level() + 1..M::LEVELS).rev() {
+            let pte = &mut unsafe { table(table_pa, self.phys_offset) }[vpn(va, level)];
+            if *pte & Flags::VALID.bits() == 0 {
+                let frame = frames.alloc().ok_or(MapError::OutOfFrames)?;
+                unsafe { table(frame, self.phys_offset) }
+                    .iter_mut()
+                    .for_each(|pte| *pte = 0);
+                *pte = make_pte(frame, Flags::VALID);
+            } else if is_leaf(*pte) {
+                return Err(MapError::AlreadyMapped);
+            }
+            table_pa = pte_pa(*pte);
+        }
+        let pte = &mut unsafe { table(table_pa, self.phys_offset) }[vpn(va, size.level())];
+        if *pte & Flags::VALID.bits() != 0 {
+            return Err(MapError::AlreadyMapped);
+        }
+        *pte = make_pte(pa, flags | Flags::VALID);
+        Ok(())
+    }
+
+    /// Unmap the page containing `va`, returns its physical address and size
+    ///
+    /// Page tables emptied by unmapping are not freed.
+    pub fn unmap(&mut self, va: usize) -> Result<(usize, PageSize), UnmapError> {
+        let (pte, level) = self.walk(va).ok_or(UnmapError::NotMapped)?;
+        let size = PageSize::from_level(level).ok_or(UnmapError::UnsupportedPageSize)?;
+        let pa = pte_pa(unsafe { *pte });
+        unsafe { *pte = 0 };
+        Ok((pa, size))
+    }
+
+    /// Split the page containing `va` down to 4K pages with the same flags
+    ///
+    /// Page tables for the smaller pages are allocated from `frames`. Addresses keep their
+    /// translations, so this works on the running page table too, e.g. on the gigapages of
+    /// a boot page. Flush the TLB to take effect.
+    pub fn split(&mut self, va: usize, frames: &mut impl FrameAllocator) -> Result<(), UnmapError> {
+        loop {
+            let (pte, level) = self.walk(va).ok_or(UnmapError::NotMapped)?;
+            if level == 0 {
+                return Ok(());
+            }
+            let leaf = unsafe { *pte };
+            let frame = frames.alloc().ok_or(UnmapError::OutOfFrames)?;
+            let size = 1 << (PAGE_SHIFT + LEVEL_BITS * (level - 1));
+            let flags = Flags::from_bits_truncate(leaf);
+            for (i, entry) in unsafe { table(frame, self.phys_offset) }
+                .iter_mut()
+                .enumerate()
+            {
+                *entry = make_pte(pte_pa(leaf) + i * size, flags);
+            }
+            unsafe { *pte = make_pte(frame, Flags::VALID) };
+        }
+    }
+
+    /// Unmap the lowest 4K page of each hart stack as a guard page
+    ///
+    /// Bigger pages mapping the guard pages, e.g. the gigapages of a boot page, are split
+    /// first with page tables from `frames`, see [`split`](Self::split). Each hart could use
+    /// `_hart_stack_size - 4K` of stack afterwards, and stack canaries are no longer checked.
+    /// Flush the TLB to take effect.
+    pub fn unmap_stack_guards(
+        &mut self,
+        frames: &mut impl FrameAllocator,
+    ) -> Result<(), UnmapError> {
+        let guards = || (0..=crate::max_hart_id()).map(|hartid| crate::stack::bounds(hartid).0);
+        for guard in guards() {
+            self.split(guard, frames)?;
+        }
+        crate::stack::set_guarded();
+        for guard in guards() {
+            self.unmap(guard)?;
+        }
+        Ok(())
+    }
+
+    /// Translate `va` into physical address, with the flags of its page
+    pub fn translate(&self, va: usize) -> Option<(usize, Flags)> {
+        let (pte, level) = self.walk(va)?;
+        let pte = unsafe { *pte };
+        let offset = va & ((1 << (PAGE_SHIFT + LEVEL_BITS * level)) - 1);
+        Some((pte_pa(pte) + offset, Flags::from_bits_truncate(pte)))
+    }
+
+    // Find the leaf entry mapping `va`, with its level
+    fn walk(&self, va: usize) -> Option<(*mut usize, usize)> {
+        if !is_canonical::<M>(va) {
+            return None;
+        }
+        let mut table_pa = self.root;
+        for level in (0..M::LEVELS).rev() {
+            let pte = &mut unsafe { table(table_pa, self.phys_offset) }[vpn(va, level)];
+            if *pte & Flags::VALID.bits() == 0 {
+                return None;
+            }
+            if is_leaf(*pte) {
+                return Some((pte as *mut usize, level));
+            }
+            table_pa = pte_pa(*pte);
+        }
+        None
+    }
+}
+
+/// Frame allocator over a physical range, recycling freed frames in a linked list
+///
+/// Free frames hold the physical address of the next free frame in their first word.
+pub struct BumpFrameAllocator {
+    next: usize,
+    end: usize,
+    free: Option<usize>,
+    phys_offset: usize,
+}
+
+impl BumpFrameAllocator {
+    /// Allocate frames in physical range `start..end`, accessed at `pa + phys_offset`
+    pub const fn new(start: usize, end: usize, phys_offset: usize) -> Self {
+        BumpFrameAllocator {
+            next: start,
+            end,
+            free: None,
+            phys_offset,
+        }
+    }
+
+    /// Allocate frames in the `.frame` section of size `_frame_size`
+    pub fn from_frame_region() -> Self {
+        let phys_offset = crate::va_pa_offset();
+        Self::new(
+            (crate::frame_start() as usize).wrapping_sub(phys_offset),
+            (crate::frame_end() as usize).wrapping_sub(phys_offset),
+            phys_offset,
+        )
+    }
+}
+
+impl FrameAllocator for BumpFrameAllocator {
+    fn alloc(&mut self) -> Option<usize> {
+        if let Some(pa) = self.free {
+            let next = unsafe { *(pa.wrapping_add(self.phys_offset) as *const usize) };
+            self.free = if next == usize::MAX { None } else { Some(next) };
+            Some(pa)
+        } else if self.next < self.end {
+            let pa = self.next;
+            self.next += 1 << PAGE_SHIFT;
+            Some(pa)
+        } else {
+            None
+        }
+    }
+
+    fn dealloc(&mut self, pa: usize) {
+        let next = self.free.unwrap_or(usize::MAX);
+        unsafe { *(pa.wrapping_add(self.phys_offset) as *mut usize) = next };
+        self.free = Some(pa);
+    }
+}
+
+#[inline]
+unsafe fn table<'a>(pa: usize, phys_offset: usize) -> &'a mut [usize; ENTRIES] {
+    &mut *(pa.wrapping_add(phys_offset) as *mut [usize; ENTRIES])
+}
+
+#[inline]
+fn vpn(va: usize, level: usize) -> usize {
+    (va >> (PAGE_SHIFT + LEVEL_BITS * level)) & (ENTRIES - 1)
+}
+
+#[inline]
+fn make_pte(pa: usize, flags: Flags) -> usize {
+    (pa >> PAGE_SHIFT) << 10 | flags.bits()
+}
+
+#[inline]
+fn pte_pa(pte: usize) -> usize {
+    ((pte >> 10) & PPN_MASK) << PAGE_SHIFT
+}
+
+// Entries with any of R, W or X bits are leaves; otherwise they point to the next level
+#[inline]
+fn is_leaf(pte: usize) -> bool {
+    pte & (Flags::READABLE | Flags::WRITABLE | Flags::EXECUTABLE).bits() != 0
+}
+
+// Bits above the virtual address width must all equal to its highest bit
+#[inline]
+fn is_canonical<M: Mode>(va: usize) -> bool {
+    let bits = PAGE_SHIFT + LEVEL_BITS * M::LEVELS;
+    let sign_extended = ((va << (64 - bits)) as isize >> (64 - bits)) as usize;
+    sign_extended == va
+}
@@ -0,0 +1,352 @@
+//! PLIC driver for supervisor external interrupts
+//!
+//! [`init`] finds the PLIC in the device tree, and the S-mode context of each hart from its
+//! `interrupts-extended`. The default `SupervisorExternal` handler claims interrupts in the
+//! context of current hart, calls the handler registered for each IRQ with
+//! [`external_interrupt`](crate::external_interrupt), and completes them.
+//!
+//! ```ignore
+//! #[external_interrupt(irq = 10)]
+//! fn uart() {
+//!     while let Some(byte) = uart.receive() { ... }
+//! }
+//!
+//! plic::init(&fdt);
+//! plic::set_priority(10, 1)?;
+//! plic::enable(10)?;
+//! unsafe { sstatus::set_sie() };
+//! ```
+//!
+//! The PLIC is accessed at its address in the device tree. With paging, map it and call
+//! [`set_base`] with the mapped address. If you define your own `SupervisorExternal` with
+//! `#[interrupt]`, call [`handle_interrupt`] from it.
+
+use crate::fdt::Fdt;
+use crate::hart::{self, HartLocal};
+use crate::sbi::HartMask;
+use core::convert::TryInto;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+
+/// Largest number of interrupt sources of a PLIC, including the reserved source 0
+pub const MAX_SOURCES: usize = 1024;
+
+/// Maximum number of handlers registered with [`register_handler`]
+pub const MAX_REGISTERED: usize = 16;
+
+// Register offsets from the PLIC base
+const PRIORITY: usize = 0;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0;
+const CLAIM: usize = 4;
+
+// Supervisor external interrupt of the hart-local interrupt controller
+const IRQ_S_EXT: u32 = 9;
+const NO_CONTEXT: usize = usize::MAX;
+
+static BASE: AtomicUsize = AtomicUsize::new(0);
+// `riscv,ndev`, the largest valid IRQ
+static SOURCES: AtomicUsize = AtomicUsize::new(0);
+// Enable words of a context may be changed by any hart
+static ENABLE_LOCK: AtomicBool = AtomicBool::new(false);
+
+// `(irq, fn())` pairs of handlers registered at runtime; zero irq for free entries
+static REGISTERED: [(AtomicUsize, AtomicUsize); MAX_REGISTERED] = [
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+    (AtomicUsize::new(0), AtomicUsize::new(0)),
+];
+
+#[link_section = ".hart_local"]
+static HART_CONTEXT: HartLocal<AtomicUsize> = HartLocal::new(AtomicUsize::new(NO_CONTEXT));
+
+/// Error of PLIC operations
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PlicError {
+    /// IRQ is 0 or above `riscv,ndev`
+    InvalidIrq(usize),
+    /// Hart has no S-mode context, or its id is above `max_hart_id`
+    NoContext(usize),
+}
+
+// Entry of the `.external_interrupts` table, made by `#[external_interrupt]`
+#[doc(hidden)]
+#[repr(C)]
+pub struct ExternalInterrupt {
+    pub irq: usize,
+    pub handler: fn(),
+}
+
+/// Find the PLIC and the S-mode context of each hart in the device tree
+///
+/// Sets the threshold of each context to 0 and disables all sources in them.
+/// Returns false if there's no `riscv,plic0` or `sifive,plic-1.0.0` node.
+pub fn init(fdt: &Fdt) -> bool {
+    let plic = match fdt.find_compatible(&["riscv,plic0", "sifive,plic-1.0.0"]) {
+        Some(plic) => plic,
+        None => return false,
+    };
+    let base = match plic.reg().next() {
+        Some((base, _)) => base as usize,
+        None => return false,
+    };
+    let sources = plic
+        .property_u32("riscv,ndev")
+        .map_or(MAX_SOURCES - 1, |n| n as usize);
+    BASE.store(base, Ordering::Relaxed);
+    SOURCES.store(sources.min(MAX_SOURCES - 1), Ordering::Relaxed);
+
+    // Context i is the i-th `<phandle irq>` pair, of the interrupt controller of a cpu
+    let contexts = plic.property("interrupts-extended").unwrap_or(&[]);
+    for (context, pair) in contexts.chunks_exact(8).enumerate() {
+        let phandle = u32::from_be_bytes(pair[..4].try_into().unwrap());
+        let irq = u32::from_be_bytes(pair[4..].try_into().unwrap());
+        if irq != IRQ_S_EXT {
+            continue;
+        }
+        let hartid = match fdt.hart_of_controller(phandle) {
+            Some(hartid) if hartid <= crate::max_hart_id() => hartid,
+            _ => continue,
+        };
+        unsafe { &*HART_CONTEXT.as_ptr_of(hartid) }.store(context, Ordering::Relaxed);
+        write(context_register(context, THRESHOLD), 0);
+        for word in 0..=sources / 32 {
+            write(enable_register(context, word * 32), 0);
+        }
+    }
+    true
+}
+
+/// Access the PLIC at `base` from now on, e.g. after mapping it to a virtual address
+#[inline]
+pub fn set_base(base: usize) {
+    BASE.store(base, Ordering::Relaxed);
+}
+
+/// Set priority of `irq`; 0 never interrupts, higher values take precedence
+pub fn set_priority(irq: usize, priority: u32) -> Result<(), PlicError> {
+    check(irq)?;
+    write(PRIORITY + irq * 4, priority);
+    Ok(())
+}
+
+/// Priority of `irq`
+pub fn priority(irq: usize) -> Result<u32, PlicError> {
+    check(irq)?;
+    Ok(read(PRIORITY + irq * 4))
+}
+
+/// Whether `irq` is pending
+pub fn is_pending(irq: usize) -> Result<bool, PlicError> {
+    check(irq)?;
+    Ok(read(PENDING + irq / 32 * 4) & (1 << (irq % 32)) != 0)
+}
+
+/// Enable `irq` for current hart
+pub fn enable(irq: usize) -> Result<(), PlicError> {
+    check(irq)?;
+    set_enabled(current_context()?, irq, true);
+    Ok(())
+}
+
+/// Disable `irq` for current hart
+pub fn disable(irq: usize) -> Result<(), PlicError> {
+    check(irq)?;
+    set_enabled(current_context()?, irq, false);
+    Ok(())
+}
+
+/// Enable `irq` for `harts` only
+///
+/// Harts without an S-mode context are skipped. The PLIC delivers `irq` to one of them.
+/// Fails without changing anything if `harts` has a hart above `max_hart_id`.
+pub fn set_affinity(irq: usize, harts: HartMask) -> Result<(), PlicError> {
+    check(irq)?;
+    match harts.last() {
+        Some(hartid) if hartid > crate::max_hart_id() => return Err(PlicError::NoContext(hartid)),
+        _ => {}
+    }
+    for hartid in 0..=crate::max_hart_id() {
+        let context = unsafe { &*HART_CONTEXT.as_ptr_of(hartid) }.load(Ordering::Relaxed);
+        if context != NO_CONTEXT {
+            set_enabled(context, irq, harts.contains(hartid));
+        }
+    }
+    Ok(())
+}
+
+/// Set the priority threshold of current hart; only interrupts above it are delivered
+pub fn set_threshold(threshold: u32) -> Result<(), PlicError> {
+    write(context_register(current_context()?, THRESHOLD), threshold);
+    Ok(())
+}
+
+/// Claim the highest priority pending interrupt of current hart
+///
+/// Always `None` on a hart without an S-mode context.
+#[inline]
+pub fn claim() -> Option<usize> {
+    claim_in(current_context().ok()?)
+}
+
+/// Complete a claimed interrupt of current hart
+#[inline]
+pub fn complete(irq: usize) -> Result<(), PlicError> {
+    check(irq)?;
+    complete_in(current_context()?, irq);
+    Ok(())
+}
+
+/// Claim, handle and complete all pending interrupts of current hart
+///
+/// Called by the default `SupervisorExternal` handler. Interrupts without a handler
+/// registered with [`external_interrupt`](crate::external_interrupt) are logged and
+/// disabled for current hart, so a level-triggered source doesn't interrupt again at once.
+/// On a hart without an S-mode context, nothing can be claimed, so supervisor external
+/// interrupts are disabled in `sie` instead.
+pub fn handle_interrupt() {
+    let context = match current_context() {
+        Ok(context) => context,
+        Err(_) => {
+            crate::disable_interrupt(crate::Interrupt::SupervisorExternal);
+            crate::logger::warn_in_interrupt(format_args!(
+                "external interrupt without a PLIC context, disabled"
+            ));
+            return;
+        }
+    };
+    while let Some(irq) = claim_in(context) {
+        match handler(irq) {
+            Some(handler) => handler(),
+            None => {
+                crate::logger::warn_in_interrupt(format_args!(
+                    "unhandled external interrupt {}, disabled",
+                    irq
+                ));
+                set_enabled(context, irq, false);
+            }
+        }
+        complete_in(context, irq);
+    }
+}
+
+/// Register `handler` for `irq` at runtime, e.g. for an IRQ found in the device tree
+///
+/// Handlers of `#[external_interrupt]` come first. Returns false if all
+/// [`MAX_REGISTERED`] entries are taken.
+pub fn register_handler(irq: usize, handler: fn()) -> bool {
+    for (entry_irq, entry_handler) in REGISTERED.iter() {
+        // Claim a free entry, then publish its handler
+        if entry_irq
+            .compare_exchange(0, usize::MAX, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            entry_handler.store(handler as usize, Ordering::Relaxed);
+            entry_irq.store(irq, Ordering::Release);
+            return true;
+        }
+    }
+    false
+}
+
+// Handler registered for `irq`, also used for IMSIC interrupt identities
+pub(crate) fn handler(irq: usize) -> Option<fn()> {
+    extern "C" {
+        static _sexternal_interrupts: ExternalInterrupt;
+        static _eexternal_interrupts: ExternalInterrupt;
+    }
+    let table = unsafe {
+        let start = &_sexternal_interrupts as *const ExternalInterrupt;
+        let end = &_eexternal_interrupts as *const ExternalInterrupt;
+        let len = (end as usize - start as usize) / core::mem::size_of::<ExternalInterrupt>();
+        core::slice::from_raw_parts(start, len)
+    };
+    if let Some(entry) = table.iter().find(|entry| entry.irq == irq) {
+        return Some(entry.handler);
+    }
+    REGISTERED
+        .iter()
+        .find(|(entry_irq, _)| entry_irq.load(Ordering::Acquire) == irq)
+        .map(|(_, handler)| {
+            let handler = handler.load(Ordering::Relaxed);
+            unsafe { core::mem::transmute::<usize, fn()>(handler) }
+        })
+}
+
+fn current_context() -> Result<usize, PlicError> {
+    match HART_CONTEXT.with(|context| context.load(Ordering::Relaxed)) {
+        NO_CONTEXT => Err(PlicError::NoContext(hart::hart_id())),
+        context => Ok(context),
+    }
+}
+
+fn claim_in(context: usize) -> Option<usize> {
+    match read(context_register(context, CLAIM)) {
+        0 => None,
+        irq => Some(irq as usize),
+    }
+}
+
+fn complete_in(context: usize, irq: usize) {
+    write(context_register(context, CLAIM), irq as u32);
+}
+
+fn set_enabled(context: usize, irq: usize, enabled: bool) {
+    let register = enable_register(context, irq);
+    crate::without_interrupts(|| {
+        while ENABLE_LOCK.swap(true, Ordering::Acquire) {
+            spin_loop_hint();
+        }
+        let bits = read(register);
+        let bit = 1 << (irq % 32);
+        write(register, if enabled { bits | bit } else { bits & !bit });
+        ENABLE_LOCK.store(false, Ordering::Release);
+    });
+}
+
+#[inline]
+fn check(irq: usize) -> Result<(), PlicError> {
+    if irq != 0 && irq <= SOURCES.load(Ordering::Relaxed) {
+        Ok(())
+    } else {
+        Err(PlicError::InvalidIrq(irq))
+    }
+}
+
+#[inline]
+fn enable_register(context: usize, irq: usize) -> usize {
+    ENABLE + context * ENABLE_STRIDE + irq / 32 * 4
+}
+
+#[inline]
+fn context_register(context: usize, register: usize) -> usize {
+    CONTEXT + context * CONTEXT_STRIDE + register
+}
+
+#[inline]
+fn read(offset: usize) -> u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { ((base + offset) as *const u32).read_volatile() }
+}
+
+#[inline]
+fn write(offset: usize, value: u32) {
+    let base = BASE.load(Ordering::Relaxed);
+    unsafe { ((base + offset) as *mut u32).write_volatile(value) }
+}
@@ -0,0 +1,120 @@
+//! Self-relocation of a position-independent supervisor
+//!
+//! Until relocations are applied, code here must not touch any static holding a pointer,
+//! nor call through a function pointer; symbol addresses are loaded pc-relative.
+//! Relocations are applied once, by the first hart to arrive: applying them again would
+//! undo pointers in .data changed at runtime, like the logger of the `log` crate.
+
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+#[repr(C)]
+struct Rela {
+    offset: usize,
+    info: usize,
+    addend: usize,
+}
+
+const R_RISCV_RELATIVE: usize = 3;
+
+const PENDING: usize = 0;
+const APPLYING: usize = 1;
+const APPLIED: usize = 2;
+
+// Read before .bss is zeroed; copying .data onto itself at init leaves it unchanged
+#[link_section = ".data"]
+static STATE: AtomicUsize = AtomicUsize::new(PENDING);
+
+/// Apply `R_RISCV_RELATIVE` relocations in `.rela.dyn` for the actual load address
+///
+/// Only the first call applies them; concurrent calls wait for it, later ones return
+/// right away, e.g. on secondary harts started by SBI HSM.
+#[inline(always)]
+pub unsafe fn relocate() {
+    match STATE.compare_exchange(PENDING, APPLYING, Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => {
+            let offset = load_base().wrapping_sub(link_base());
+            apply(offset, offset);
+            STATE.store(APPLIED, Ordering::Release);
+        }
+        Err(_) => {
+            while STATE.load(Ordering::Acquire) != APPLIED {
+                spin_loop_hint();
+            }
+        }
+    }
+}
+
+/// Apply relocations at `linked address + target_offset`, with values of
+/// `linked address + value_offset`
+///
+/// Different offsets prepare the image to run at another address, e.g. in the higher half
+/// before paging is enabled. Unlike [`relocate`], this applies them on every call; call it
+/// once, after [`relocate`].
+#[inline(always)]
+pub unsafe fn apply(target_offset: usize, value_offset: usize) {
+    let (start, end): (usize, usize);
+    llvm_asm!("
+        lla     $0, _srela_dyn
+        lla     $1, _erela_dyn
+    " : "=r"(start), "=r"(end));
+
+    let mut rela = start as *const Rela;
+    while (rela as usize) < end {
+        let Rela {
+            offset,
+            info,
+            addend,
+        } = rela.read();
+        if info == R_RISCV_RELATIVE {
+            let target = offset.wrapping_add(target_offset) as *mut usize;
+            target.write_volatile(addend.wrapping_add(value_offset));
+        }
+        rela = rela.add(1);
+    }
+}
+
+/// Address `__executable_start` is running at
+#[inline(always)]
+pub fn load_base() -> usize {
+    let base;
+    unsafe { llvm_asm!("lla $0, __executable_start" : "=r"(base)) };
+    base
+}
+
+/// Linked address of `__executable_start`, from absolute symbol `_stext`
+#[cfg(target_pointer_width = "32")]
+#[inline(always)]
+pub fn link_base() -> usize {
+    let base;
+    unsafe {
+        llvm_asm!("
+            lla     $0, 1f
+            lw      $0, 0($0)
+            j       2f
+            .align  2
+        1:
+            .word   _stext
+        2:
+        " : "=r"(base));
+    }
+    base
+}
+
+/// Linked address of `__executable_start`, from absolute symbol `_stext`
+#[cfg(target_pointer_width = "64")]
+#[inline(always)]
+pub fn link_base() -> usize {
+    let base;
+    unsafe {
+        llvm_asm!("
+            lla     $0, 1f
+            ld      $0, 0($0)
+            j       2f
+            .align  3
+        1:
+            .dword  _stext
+        2:
+        " : "=r"(base));
+    }
+    base
+}
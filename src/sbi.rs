@@ -0,0 +1,500 @@
+//! SBI v0.2+ extensions used by the runtime
+//!
+//! The Base extension is probed at boot, see [`sbi_features`]. Shutdown, timer, IPI,
+//! remote fences and the console use the detected extension, or the legacy SBI v0.1 call
+//! if it's missing.
+//!
+//! Ref: https://github.com/riscv/riscv-sbi-doc
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Error returned by an SBI call
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SbiError {
+    /// SBI_ERR_FAILED
+    Failed,
+    /// SBI_ERR_NOT_SUPPORTED
+    NotSupported,
+    /// SBI_ERR_INVALID_PARAM
+    InvalidParam,
+    /// SBI_ERR_DENIED
+    Denied,
+    /// SBI_ERR_INVALID_ADDRESS
+    InvalidAddress,
+    /// SBI_ERR_ALREADY_AVAILABLE
+    AlreadyAvailable,
+    /// Error code not defined by the spec
+    Other(isize),
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            code => SbiError::Other(code),
+        }
+    }
+}
+
+/// Set of harts, as `hart_mask` bits starting from hart `hart_mask_base`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HartMask {
+    mask: usize,
+    base: usize,
+}
+
+impl HartMask {
+    /// Harts with bit `i` set in `mask` are hart `base + i`
+    #[inline]
+    pub const fn new(mask: usize, base: usize) -> Self {
+        HartMask { mask, base }
+    }
+
+    /// Only hart `hartid`
+    #[inline]
+    pub const fn single(hartid: usize) -> Self {
+        HartMask::new(1, hartid)
+    }
+
+    /// All harts in the system
+    #[inline]
+    pub const fn all() -> Self {
+        // hart_mask_base of -1 means all available harts
+        HartMask::new(0, usize::MAX)
+    }
+
+    /// The largest hart in the set, `None` for all harts or an empty set
+    #[inline]
+    pub fn last(&self) -> Option<usize> {
+        if self.base == usize::MAX || self.mask == 0 {
+            return None;
+        }
+        let bits = core::mem::size_of::<usize>() * 8;
+        self.base
+            .checked_add(bits - 1 - self.mask.leading_zeros() as usize)
+            .or(Some(usize::MAX))
+    }
+
+    /// Whether hart `hartid` is in the set
+    #[inline]
+    pub fn contains(&self, hartid: usize) -> bool {
+        if self.base == usize::MAX {
+            return true;
+        }
+        match hartid.checked_sub(self.base) {
+            Some(bit) if bit < core::mem::size_of::<usize>() * 8 => (self.mask >> bit) & 1 != 0,
+            _ => false,
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// SBI extensions beyond Base
+    #[derive(Default)]
+    pub struct Extensions: usize {
+        /// Timer extension
+        const TIME =    1 << 0;
+        /// IPI extension
+        const IPI =     1 << 1;
+        /// RFENCE extension
+        const RFENCE =  1 << 2;
+        /// Hart state management extension
+        const HSM =     1 << 3;
+        /// System reset extension
+        const SRST =    1 << 4;
+        /// Performance monitoring unit extension
+        const PMU =     1 << 5;
+        /// Debug console extension
+        const DBCN =    1 << 6;
+    }
+}
+
+/// SBI implementation and extensions detected at boot
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SbiFeatures {
+    /// Major and minor version of the SBI spec; `(0, 1)` for legacy-only implementations
+    pub spec_version: (usize, usize),
+    /// Implementation id, e.g. 0 for BBL, 1 for OpenSBI and 4 for RustSBI
+    pub impl_id: usize,
+    /// Version of the implementation, in a format specific to it
+    pub impl_version: usize,
+    /// Available extensions
+    pub extensions: Extensions,
+}
+
+const EXTENSION_BASE: usize = 0x10;
+const EXTENSION_TIME: usize = 0x5449_4D45;
+const EXTENSION_IPI: usize = 0x0073_5049;
+const EXTENSION_RFENCE: usize = 0x5246_4E43;
+const EXTENSION_HSM: usize = 0x0048_534D;
+const EXTENSION_SRST: usize = 0x5352_5354;
+const EXTENSION_PMU: usize = 0x0050_4D55;
+const EXTENSION_DBCN: usize = 0x4442_434E;
+
+const FUNCTION_BASE_GET_SPEC_VERSION: usize = 0;
+const FUNCTION_BASE_GET_IMPL_ID: usize = 1;
+const FUNCTION_BASE_GET_IMPL_VERSION: usize = 2;
+const FUNCTION_BASE_PROBE_EXTENSION: usize = 3;
+
+const FUNCTION_TIME_SET_TIMER: usize = 0;
+const FUNCTION_IPI_SEND_IPI: usize = 0;
+const FUNCTION_SRST_SYSTEM_RESET: usize = 0;
+const FUNCTION_HSM_HART_GET_STATUS: usize = 2;
+
+const FUNCTION_DBCN_CONSOLE_WRITE: usize = 0;
+const FUNCTION_DBCN_CONSOLE_READ: usize = 1;
+const FUNCTION_DBCN_CONSOLE_WRITE_BYTE: usize = 2;
+
+const FUNCTION_RFENCE_REMOTE_FENCE_I: usize = 0;
+const FUNCTION_RFENCE_REMOTE_SFENCE_VMA: usize = 1;
+const FUNCTION_RFENCE_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+const LEGACY_SET_TIMER: usize = 0;
+const LEGACY_CONSOLE_PUTCHAR: usize = 1;
+const LEGACY_CONSOLE_GETCHAR: usize = 2;
+const LEGACY_SEND_IPI: usize = 4;
+const LEGACY_REMOTE_FENCE_I: usize = 5;
+const LEGACY_REMOTE_SFENCE_VMA: usize = 6;
+const LEGACY_REMOTE_SFENCE_VMA_ASID: usize = 7;
+const LEGACY_SHUTDOWN: usize = 8;
+
+// Spec version 0.1 until probed
+static SPEC_VERSION: AtomicUsize = AtomicUsize::new(1);
+static IMPL_ID: AtomicUsize = AtomicUsize::new(0);
+static IMPL_VERSION: AtomicUsize = AtomicUsize::new(0);
+static EXTENSIONS: AtomicUsize = AtomicUsize::new(0);
+
+// Probe the Base extension, on the boot hart after .bss and .data are initialized
+pub(crate) fn probe() {
+    // Legacy implementations don't know the Base extension and return an error
+    let spec_version = match sbi_call(EXTENSION_BASE, FUNCTION_BASE_GET_SPEC_VERSION, [0; 5]) {
+        Ok(version) => version,
+        Err(_) => return,
+    };
+    let base = |function| sbi_call(EXTENSION_BASE, function, [0; 5]).unwrap_or(0);
+    let mut extensions = Extensions::empty();
+    for &(extension, flag) in &[
+        (EXTENSION_TIME, Extensions::TIME),
+        (EXTENSION_IPI, Extensions::IPI),
+        (EXTENSION_RFENCE, Extensions::RFENCE),
+        (EXTENSION_HSM, Extensions::HSM),
+        (EXTENSION_SRST, Extensions::SRST),
+        (EXTENSION_PMU, Extensions::PMU),
+        (EXTENSION_DBCN, Extensions::DBCN),
+    ] {
+        let probe = [extension, 0, 0, 0, 0];
+        if sbi_call(EXTENSION_BASE, FUNCTION_BASE_PROBE_EXTENSION, probe).unwrap_or(0) != 0 {
+            extensions |= flag;
+        }
+    }
+    IMPL_ID.store(base(FUNCTION_BASE_GET_IMPL_ID), Ordering::Relaxed);
+    IMPL_VERSION.store(base(FUNCTION_BASE_GET_IMPL_VERSION), Ordering::Relaxed);
+    EXTENSIONS.store(extensions.bits(), Ordering::Relaxed);
+    SPEC_VERSION.store(spec_version, Ordering::Relaxed);
+}
+
+/// SBI implementation and extensions detected at boot
+///
+/// Before `main` it reports a legacy-only implementation.
+pub fn sbi_features() -> SbiFeatures {
+    let spec_version = SPEC_VERSION.load(Ordering::Relaxed);
+    SbiFeatures {
+        spec_version: ((spec_version >> 24) & 0x7f, spec_version & 0xff_ffff),
+        impl_id: IMPL_ID.load(Ordering::Relaxed),
+        impl_version: IMPL_VERSION.load(Ordering::Relaxed),
+        extensions: Extensions::from_bits_truncate(EXTENSIONS.load(Ordering::Relaxed)),
+    }
+}
+
+#[inline]
+fn has(extension: Extensions) -> bool {
+    Extensions::from_bits_truncate(EXTENSIONS.load(Ordering::Relaxed)).contains(extension)
+}
+
+/// Type of system reset
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResetType {
+    /// Power down the system
+    Shutdown = 0,
+    /// Power cycle the whole system
+    ColdReboot = 1,
+    /// Reboot the harts and some devices, keeping power on
+    WarmReboot = 2,
+}
+
+/// Reason of system reset
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResetReason {
+    /// Normal reset, e.g. QEMU exits with status 0
+    NoReason = 0,
+    /// Reset after a failure, e.g. QEMU exits with status 1
+    SystemFailure = 1,
+}
+
+/// Reset the system with SRST extension
+///
+/// Returns only on failure. Without SRST, shutdown falls back to the legacy call and reboots
+/// return `SbiError::NotSupported`.
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+    if has(Extensions::SRST) {
+        let args = [reset_type as usize, reason as usize, 0, 0, 0];
+        if let Err(error) = sbi_call(EXTENSION_SRST, FUNCTION_SRST_SYSTEM_RESET, args) {
+            return error;
+        }
+    }
+    if reset_type == ResetType::Shutdown {
+        legacy_call(LEGACY_SHUTDOWN, [0; 4]);
+        SbiError::Failed
+    } else {
+        SbiError::NotSupported
+    }
+}
+
+/// Shut down the system, or halt current hart if it fails
+pub fn shutdown() -> ! {
+    system_reset(ResetType::Shutdown, ResetReason::NoReason);
+    loop {
+        unsafe { llvm_asm!("wfi" :::: "volatile") };
+    }
+}
+
+/// Program the timer of current hart to fire at `stime_value`, clearing pending timer interrupt
+pub fn set_timer(stime_value: u64) {
+    #[cfg(target_pointer_width = "32")]
+    let args = [stime_value as usize, (stime_value >> 32) as usize];
+    #[cfg(target_pointer_width = "64")]
+    let args = [stime_value as usize, 0];
+    if has(Extensions::TIME) {
+        let _ = sbi_call(
+            EXTENSION_TIME,
+            FUNCTION_TIME_SET_TIMER,
+            [args[0], args[1], 0, 0, 0],
+        );
+    } else {
+        legacy_call(LEGACY_SET_TIMER, [args[0], args[1], 0, 0]);
+    }
+}
+
+/// Write `bytes` to the debug console, returning how many bytes are written
+///
+/// With DBCN, SBI reads `bytes` at its physical address, found with
+/// [`va_pa_offset`](crate::va_pa_offset) if `bytes` is in the kernel image, e.g. on a hart
+/// stack or in the heap. Other buffers, and all buffers without DBCN, are written byte
+/// by byte with [`console_putchar`].
+pub fn console_write(bytes: &[u8]) -> Result<usize, SbiError> {
+    let (lo, hi) = match physical(bytes.as_ptr(), bytes.len()) {
+        Some(address) if has(Extensions::DBCN) => address,
+        _ => {
+            bytes.iter().for_each(|&byte| console_putchar(byte));
+            return Ok(bytes.len());
+        }
+    };
+    sbi_call(
+        EXTENSION_DBCN,
+        FUNCTION_DBCN_CONSOLE_WRITE,
+        [bytes.len(), lo, hi, 0, 0],
+    )
+}
+
+/// Write a byte to the debug console
+#[inline]
+pub fn console_putchar(byte: u8) {
+    if has(Extensions::DBCN) {
+        let args = [byte as usize, 0, 0, 0, 0];
+        let _ = sbi_call(EXTENSION_DBCN, FUNCTION_DBCN_CONSOLE_WRITE_BYTE, args);
+    } else {
+        legacy_call(LEGACY_CONSOLE_PUTCHAR, [byte as usize, 0, 0, 0]);
+    }
+}
+
+/// Read a byte from the debug console, if any
+pub fn console_getchar() -> Option<u8> {
+    if has(Extensions::DBCN) {
+        let mut byte = 0u8;
+        if let Some((lo, hi)) = physical(&mut byte, 1) {
+            let args = [1, lo, hi, 0, 0];
+            return match sbi_call(EXTENSION_DBCN, FUNCTION_DBCN_CONSOLE_READ, args) {
+                Ok(1) => Some(byte),
+                _ => None,
+            };
+        }
+    }
+    match legacy_call(LEGACY_CONSOLE_GETCHAR, [0; 4]) as isize {
+        byte if byte >= 0 => Some(byte as u8),
+        _ => None,
+    }
+}
+
+// Low and high words of the physical address of `len` bytes at `ptr`, `None` unless they're
+// in the kernel image, from `.text` to the last runtime section. The image is entered
+// without paging, so its physical addresses fit in the low word.
+fn physical(ptr: *const u8, len: usize) -> Option<(usize, usize)> {
+    extern "C" {
+        static __executable_start: u8;
+    }
+    let image_start = unsafe { &__executable_start as *const u8 as usize };
+    let start = ptr as usize;
+    if start < image_start || start.checked_add(len)? > crate::image_end() {
+        return None;
+    }
+    Some((start.checked_sub(crate::va_pa_offset())?, 0))
+}
+
+/// State of a hart in HSM
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HartStatus {
+    /// Running
+    Started,
+    /// Not running, may be started with `sbi_hart_start`
+    Stopped,
+    /// Being started
+    StartPending,
+    /// Being stopped
+    StopPending,
+    /// Suspended by `sbi_hart_suspend`
+    Suspended,
+    /// Being suspended
+    SuspendPending,
+    /// Resuming from suspend
+    ResumePending,
+}
+
+/// State of hart `hartid`, by HSM
+///
+/// Returns `SbiError::NotSupported` without HSM, and `SbiError::InvalidParam` for a hart
+/// that doesn't exist.
+pub fn hart_get_status(hartid: usize) -> Result<HartStatus, SbiError> {
+    if !has(Extensions::HSM) {
+        return Err(SbiError::NotSupported);
+    }
+    let args = [hartid, 0, 0, 0, 0];
+    match sbi_call(EXTENSION_HSM, FUNCTION_HSM_HART_GET_STATUS, args)? {
+        0 => Ok(HartStatus::Started),
+        1 => Ok(HartStatus::Stopped),
+        2 => Ok(HartStatus::StartPending),
+        3 => Ok(HartStatus::StopPending),
+        4 => Ok(HartStatus::Suspended),
+        5 => Ok(HartStatus::SuspendPending),
+        6 => Ok(HartStatus::ResumePending),
+        _ => Err(SbiError::Failed),
+    }
+}
+
+/// Send a supervisor software interrupt to `harts`
+pub fn send_ipi(harts: HartMask) -> Result<(), SbiError> {
+    if has(Extensions::IPI) {
+        sbi_call(
+            EXTENSION_IPI,
+            FUNCTION_IPI_SEND_IPI,
+            [harts.mask, harts.base, 0, 0, 0],
+        )
+        .map(drop)
+    } else {
+        legacy_with_mask(LEGACY_SEND_IPI, harts, [0; 3])
+    }
+}
+
+/// Execute `fence.i` on remote harts
+#[inline]
+pub fn remote_fence_i(harts: HartMask) -> Result<(), SbiError> {
+    if !has(Extensions::RFENCE) {
+        return legacy_with_mask(LEGACY_REMOTE_FENCE_I, harts, [0; 3]);
+    }
+    sbi_call(
+        EXTENSION_RFENCE,
+        FUNCTION_RFENCE_REMOTE_FENCE_I,
+        [harts.mask, harts.base, 0, 0, 0],
+    )
+    .map(drop)
+}
+
+/// Execute `sfence.vma` for `start..start + size` of all address spaces on remote harts
+///
+/// A `start` and `size` of zero, or `size` of `usize::MAX`, flush the whole TLB.
+#[inline]
+pub fn remote_sfence_vma(harts: HartMask, start: usize, size: usize) -> Result<(), SbiError> {
+    if !has(Extensions::RFENCE) {
+        return legacy_with_mask(LEGACY_REMOTE_SFENCE_VMA, harts, [start, size, 0]);
+    }
+    sbi_call(
+        EXTENSION_RFENCE,
+        FUNCTION_RFENCE_REMOTE_SFENCE_VMA,
+        [harts.mask, harts.base, start, size, 0],
+    )
+    .map(drop)
+}
+
+/// Execute `sfence.vma` for `start..start + size` of address space `asid` on remote harts
+#[inline]
+pub fn remote_sfence_vma_asid(
+    harts: HartMask,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> Result<(), SbiError> {
+    if !has(Extensions::RFENCE) {
+        return legacy_with_mask(LEGACY_REMOTE_SFENCE_VMA_ASID, harts, [start, size, asid]);
+    }
+    sbi_call(
+        EXTENSION_RFENCE,
+        FUNCTION_RFENCE_REMOTE_SFENCE_VMA_ASID,
+        [harts.mask, harts.base, start, size, asid],
+    )
+    .map(drop)
+}
+
+#[inline]
+fn sbi_call(extension: usize, function: usize, args: [usize; 5]) -> Result<usize, SbiError> {
+    let (error, value): (isize, usize);
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}"(error), "={x11}"(value)
+            : "{x10}"(args[0]), "{x11}"(args[1]), "{x12}"(args[2]), "{x13}"(args[3]),
+              "{x14}"(args[4]), "{x16}"(function), "{x17}"(extension)
+            : "memory"
+            : "volatile");
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(SbiError::from_code(error))
+    }
+}
+
+// Legacy calls take a pointer to the hart mask starting from hart 0
+fn legacy_with_mask(extension: usize, harts: HartMask, args: [usize; 3]) -> Result<(), SbiError> {
+    let mask = if harts.base == usize::MAX {
+        usize::MAX
+    } else if harts.base >= core::mem::size_of::<usize>() * 8 {
+        // Harts beyond XLEN can't be expressed
+        return Err(SbiError::InvalidParam);
+    } else {
+        harts
+            .mask
+            .checked_shl(harts.base as u32)
+            .filter(|mask| mask >> harts.base == harts.mask)
+            .ok_or(SbiError::InvalidParam)?
+    };
+    let mask_ptr = &mask as *const usize as usize;
+    legacy_call(extension, [mask_ptr, args[0], args[1], args[2]]);
+    Ok(())
+}
+
+#[inline]
+fn legacy_call(extension: usize, args: [usize; 4]) -> usize {
+    let ret;
+    unsafe {
+        llvm_asm!("ecall"
+            : "={x10}"(ret)
+            : "{x10}"(args[0]), "{x11}"(args[1]), "{x12}"(args[2]), "{x13}"(args[3]),
+              "{x17}"(extension)
+            : "memory"
+            : "volatile");
+    }
+    ret
+}
@@ -0,0 +1,81 @@
+//! Per-hart stacks and stack overflow detection
+//!
+//! Hart N uses the N-th `_hart_stack_size` slot from `_sstack` downwards. The lowest word
+//! of each slot holds a canary, checked by the trap entry on every trap of that hart from
+//! the kernel, before saving the context; a hart running over its stack breaks it and the
+//! trap handler panics with "stack overflow on hart N".
+//!
+//! With paging, the lowest 4K page of each slot could be unmapped as a guard page instead,
+//! see `PageTable::unmap_stack_guards`; faults in a guard page are reported the same way.
+//! Both switch to a small per-hart trap stack (`_trap_stack_size`, 16K by default), so
+//! the context isn't saved below the overflowed stack, and the panic message gets printed
+//! instead of faulting again. The trap entry finds the trap stack from the hart area,
+//! see [`hart`](crate::hart).
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Size of the guard page at the bottom of each hart stack
+pub const GUARD_SIZE: usize = 4096;
+
+// In sync with `STACK_CANARY` of the trap entry
+const CANARY: usize = 0x57ac_c0de_57ac_c0de_u64 as usize;
+
+// Set once guard pages are unmapped; canaries in them can't be read any more.
+// Read by the trap entry
+#[export_name = "_stack_guarded"]
+static GUARDED: AtomicBool = AtomicBool::new(false);
+
+/// Stack range `[bottom, top)` of hart `hartid`
+#[inline]
+pub fn bounds(hartid: usize) -> (usize, usize) {
+    extern "C" {
+        static _sstack: u8;
+        static _hart_stack_size: u8;
+    }
+    let (top, size) = unsafe {
+        (
+            &_sstack as *const _ as usize,
+            &_hart_stack_size as *const _ as usize,
+        )
+    };
+    let top = top - hartid * size;
+    (top - size, top)
+}
+
+/// Whether stack overflows are caught by guard pages rather than canaries
+#[inline]
+pub fn is_guarded() -> bool {
+    GUARDED.load(Ordering::Relaxed)
+}
+
+#[cfg(target_pointer_width = "64")]
+pub(crate) fn set_guarded() {
+    GUARDED.store(true, Ordering::Relaxed);
+}
+
+// Write canaries for all harts, on the boot hart before any trap is taken
+pub(crate) unsafe fn init_canaries() {
+    for hartid in 0..=crate::max_hart_id() {
+        (bounds(hartid).0 as *mut usize).write_volatile(CANARY);
+    }
+}
+
+/// Whether the stack canary of current hart is broken
+///
+/// Always false once guard pages are in use.
+#[inline]
+pub fn is_overflowed() -> bool {
+    if is_guarded() {
+        return false;
+    }
+    let bottom = bounds(crate::hart::hart_id()).0;
+    unsafe { (bottom as *const usize).read_volatile() != CANARY }
+}
+
+/// Find the hart whose guard page contains `addr`
+pub fn guard_of(addr: usize) -> Option<usize> {
+    (0..=crate::max_hart_id()).find(|&hartid| {
+        let bottom = bounds(hartid).0;
+        addr >= bottom && addr < bottom + GUARD_SIZE
+    })
+}
@@ -0,0 +1,311 @@
+//! Timers on top of the supervisor timer interrupt
+//!
+//! Read the timebase frequency from the device tree once with [`init`], then add one-shot
+//! or periodic timers on any hart. Timers of a hart share its single timer deadline,
+//! programmed through SBI TIME, or written to `stimecmp` directly if all harts have Sstc.
+//!
+//! ```ignore
+//! timer::init(&unsafe { Fdt::from_ptr(dtb_pa) }.unwrap());
+//! timer::add_periodic(Duration::from_millis(10), tick)?;
+//! unsafe { sstatus::set_sie() };
+//! ```
+//!
+//! The default `SupervisorTimer` handler runs the callbacks. If you define your own with
+//! `#[interrupt]`, call [`handle_interrupt`] from it.
+
+use crate::fdt::Fdt;
+use crate::hart::HartLocal;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use riscv::register::{sip, sstatus, time};
+
+/// Maximum number of pending timers on each hart
+pub const MAX_TIMERS: usize = 32;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// QEMU virt timebase until initialized
+static FREQUENCY: AtomicUsize = AtomicUsize::new(10_000_000);
+static SSTC: AtomicBool = AtomicBool::new(false);
+
+#[link_section = ".hart_local"]
+static TIMERS: HartLocal<UnsafeCell<Timers>> = HartLocal::new(UnsafeCell::new(Timers::new()));
+
+/// Error when adding a timer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TimerError {
+    /// All `MAX_TIMERS` timers of this hart are pending
+    Full,
+    /// Period of a periodic timer is zero
+    ZeroPeriod,
+}
+
+/// Handle of a pending timer, for [`cancel`] on the hart that added it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimerId {
+    slot: usize,
+    serial: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: u64,
+    // zero for one-shot timers
+    period: u64,
+    callback: fn(),
+    serial: usize,
+}
+
+struct Timers {
+    slots: [Option<Timer>; MAX_TIMERS],
+    next_serial: usize,
+}
+
+impl Timers {
+    const fn new() -> Self {
+        Timers {
+            slots: [None; MAX_TIMERS],
+            next_serial: 0,
+        }
+    }
+
+    fn next_deadline(&self) -> u64 {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|timer| timer.deadline)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+/// Read the timebase frequency and Sstc support from the device tree
+///
+/// The frequency is `timebase-frequency` of `/cpus`, or of the first cpu node.
+pub fn init(fdt: &Fdt) {
+    let cpus = fdt.find_node("/cpus");
+    let frequency = cpus
+        .and_then(|cpus| cpus.property_u64("timebase-frequency"))
+        .or_else(|| cpu_nodes(fdt).find_map(|cpu| cpu.property_u64("timebase-frequency")));
+    if let Some(frequency) = frequency {
+        FREQUENCY.store(frequency as usize, Ordering::Relaxed);
+    }
+
+    let mut cpus = cpu_nodes(fdt).peekable();
+    let sstc = cpus.peek().is_some() && cpus.all(|cpu| cpu_has_sstc(&cpu));
+    SSTC.store(sstc, Ordering::Relaxed);
+}
+
+fn cpu_nodes<'a>(fdt: &Fdt<'a>) -> impl Iterator<Item = crate::fdt::Node<'a>> {
+    fdt.nodes()
+        .filter(|node| node.property_str("device_type") == Some("cpu"))
+}
+
+fn cpu_has_sstc(cpu: &crate::fdt::Node) -> bool {
+    if let Some(extensions) = cpu.property("riscv,isa-extensions") {
+        return extensions.split(|&b| b == 0).any(|ext| ext == b"sstc");
+    }
+    // Multi-letter extensions follow single letters, separated by underscores
+    match cpu.property_str("riscv,isa") {
+        Some(isa) => isa
+            .split('_')
+            .skip(1)
+            .any(|ext| ext.eq_ignore_ascii_case("sstc")),
+        None => false,
+    }
+}
+
+/// Timebase frequency in Hz
+#[inline]
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed) as u64
+}
+
+/// Whether `stimecmp` of Sstc is used instead of SBI TIME
+#[inline]
+pub fn has_sstc() -> bool {
+    SSTC.load(Ordering::Relaxed)
+}
+
+/// Current value of the `time` CSR
+#[inline]
+pub fn now() -> u64 {
+    time::read64()
+}
+
+/// Time since the `time` CSR was zero, usually since reset
+#[inline]
+pub fn uptime() -> Duration {
+    ticks_to_duration(now())
+}
+
+/// Convert `time` ticks to duration
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let frequency = frequency();
+    let nanos = (ticks % frequency) * NANOS_PER_SEC / frequency;
+    Duration::new(ticks / frequency, nanos as u32)
+}
+
+/// Convert duration to `time` ticks, rounding down
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = frequency();
+    duration
+        .as_secs()
+        .saturating_mul(frequency)
+        .saturating_add(u64::from(duration.subsec_nanos()) * frequency / NANOS_PER_SEC)
+}
+
+/// Spin on the `time` CSR for `duration`
+pub fn delay(duration: Duration) {
+    let deadline = now().saturating_add(duration_to_ticks(duration));
+    while now() < deadline {
+        spin_loop_hint();
+    }
+}
+
+/// Wait in `wfi` until `time` reaches `deadline`
+///
+/// Interrupts arriving meanwhile are handled as usual. With interrupts disabled, the timer
+/// interrupt still wakes the hart, and timers due are run by [`handle_interrupt`] here.
+/// Spins like [`delay`] if all timers of this hart are pending.
+pub fn sleep_until(deadline: u64) {
+    let id = match add_at(deadline, wake) {
+        Ok(id) => id,
+        Err(_) => {
+            while now() < deadline {
+                spin_loop_hint();
+            }
+            return;
+        }
+    };
+    // Check the deadline with interrupts disabled, so the timer can't fire before `wfi`
+    let enabled = sstatus::read().sie();
+    unsafe { sstatus::clear_sie() };
+    while now() < deadline {
+        unsafe { riscv::asm::wfi() };
+        if enabled {
+            // Take the pending interrupts
+            unsafe {
+                sstatus::set_sie();
+                sstatus::clear_sie();
+            }
+        } else if sip::read().stimer() {
+            handle_interrupt();
+        }
+    }
+    cancel(id);
+    if enabled {
+        unsafe { sstatus::set_sie() };
+    }
+}
+
+fn wake() {}
+
+/// Call `callback` once after `delay` on current hart
+pub fn add_oneshot(delay: Duration, callback: fn()) -> Result<TimerId, TimerError> {
+    add(now().saturating_add(duration_to_ticks(delay)), 0, callback)
+}
+
+/// Call `callback` every `period` on current hart, starting after one period
+pub fn add_periodic(period: Duration, callback: fn()) -> Result<TimerId, TimerError> {
+    let period = duration_to_ticks(period);
+    if period == 0 {
+        return Err(TimerError::ZeroPeriod);
+    }
+    add(now().saturating_add(period), period, callback)
+}
+
+/// Call `callback` once when `time` reaches `deadline` on current hart
+pub fn add_at(deadline: u64, callback: fn()) -> Result<TimerId, TimerError> {
+    add(deadline, 0, callback)
+}
+
+fn add(deadline: u64, period: u64, callback: fn()) -> Result<TimerId, TimerError> {
+    crate::without_interrupts(|| {
+        let timers = unsafe { &mut *TIMERS.with(|timers| timers.get()) };
+        let slot = timers
+            .slots
+            .iter()
+            .position(Option::is_none)
+            .ok_or(TimerError::Full)?;
+        let serial = timers.next_serial;
+        timers.next_serial = serial.wrapping_add(1);
+        timers.slots[slot] = Some(Timer {
+            deadline,
+            period,
+            callback,
+            serial,
+        });
+        program(timers.next_deadline());
+        crate::enable_interrupt(crate::Interrupt::SupervisorTimer);
+        Ok(TimerId { slot, serial })
+    })
+}
+
+/// Cancel a pending timer; returns false if it already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    crate::without_interrupts(|| {
+        let timers = unsafe { &mut *TIMERS.with(|timers| timers.get()) };
+        match timers.slots[id.slot] {
+            Some(timer) if timer.serial == id.serial => {
+                timers.slots[id.slot] = None;
+                program(timers.next_deadline());
+                true
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Run callbacks of expired timers on current hart and program the next deadline
+///
+/// Called by the default `SupervisorTimer` handler, with interrupts disabled.
+pub fn handle_interrupt() {
+    let mut due: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+    {
+        let timers = unsafe { &mut *TIMERS.with(|timers| timers.get()) };
+        let now = now();
+        for (slot, due) in timers.slots.iter_mut().zip(due.iter_mut()) {
+            let timer = match slot {
+                Some(timer) if timer.deadline <= now => timer,
+                _ => continue,
+            };
+            *due = Some(timer.callback);
+            if timer.period == 0 {
+                *slot = None;
+            } else {
+                // Skip missed periods instead of firing them all at once
+                let missed = (now - timer.deadline) / timer.period;
+                timer.deadline += (missed + 1) * timer.period;
+            }
+        }
+        program(timers.next_deadline());
+    }
+    // Callbacks may add or cancel timers
+    for callback in due.iter().flatten() {
+        callback();
+    }
+}
+
+// Set the timer deadline of current hart; `u64::MAX` clears the timer interrupt
+fn program(deadline: u64) {
+    if !has_sstc() {
+        crate::sbi::set_timer(deadline);
+        return;
+    }
+    #[cfg(target_pointer_width = "64")]
+    unsafe {
+        llvm_asm!("csrw 0x14d, $0" :: "r"(deadline) :: "volatile");
+    }
+    // Avoid a spurious interrupt between writing the two halves
+    #[cfg(target_pointer_width = "32")]
+    unsafe {
+        llvm_asm!("
+            csrw    0x14d, $0
+            csrw    0x15d, $1
+            csrw    0x14d, $2
+        " :: "r"(usize::MAX), "r"((deadline >> 32) as usize), "r"(deadline as usize)
+        :: "volatile");
+    }
+}
@@ -0,0 +1,104 @@
+//! Address space identifiers and TLB shootdown
+//!
+//! After changing a mapping that other harts may have cached, flush their TLBs:
+//!
+//! ```ignore
+//! let (pa, size) = page_table.unmap(va)?;
+//! tlb::shootdown(HartMask::all(), asid, va, size.bytes())?;
+//! ```
+
+use crate::sbi::{self, HartMask, SbiError};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(target_pointer_width = "32")]
+const ASID_SHIFT: usize = 22;
+#[cfg(target_pointer_width = "32")]
+const MAX_ASID_BITS: usize = 9;
+
+#[cfg(target_pointer_width = "64")]
+const ASID_SHIFT: usize = 44;
+#[cfg(target_pointer_width = "64")]
+const MAX_ASID_BITS: usize = 16;
+
+const ASID_MASK: usize = ((1 << MAX_ASID_BITS) - 1) << ASID_SHIFT;
+const BITS_PER_WORD: usize = core::mem::size_of::<usize>() * 8;
+
+// Bit `i` is set if ASID `i` is in use; ASID 0 is kept for the kernel
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE: AtomicUsize = AtomicUsize::new(0);
+static ASID_BITMAP: [AtomicUsize; (1 << MAX_ASID_BITS) / BITS_PER_WORD] =
+    [FREE; (1 << MAX_ASID_BITS) / BITS_PER_WORD];
+
+/// Number of ASID bits implemented by this hart (ASIDLEN)
+///
+/// Detected once by writing all-ones to the ASID field of `satp` and reading it back.
+/// Zero means ASIDs are not supported; only ASID 0 could be used.
+pub fn asid_bits() -> usize {
+    static ASID_BITS: AtomicUsize = AtomicUsize::new(usize::MAX);
+    let bits = ASID_BITS.load(Ordering::Relaxed);
+    if bits != usize::MAX {
+        return bits;
+    }
+    let probe: usize;
+    unsafe {
+        llvm_asm!("
+            csrr    t0, satp
+            or      $0, t0, $1
+            csrw    satp, $0
+            csrr    $0, satp
+            csrw    satp, t0
+            sfence.vma
+        " : "=&r"(probe) : "r"(ASID_MASK) : "t0", "memory" : "volatile");
+    }
+    let bits = ((probe & ASID_MASK) >> ASID_SHIFT).count_ones() as usize;
+    ASID_BITS.store(bits, Ordering::Relaxed);
+    bits
+}
+
+/// Allocate an unused ASID
+///
+/// Returns `None` if all `2^ASIDLEN - 1` ASIDs are in use, or ASIDs are not supported.
+pub fn alloc_asid() -> Option<usize> {
+    let count = 1 << asid_bits();
+    for asid in 1..count {
+        let (word, bit) = (asid / BITS_PER_WORD, 1 << (asid % BITS_PER_WORD));
+        if ASID_BITMAP[word].fetch_or(bit, Ordering::AcqRel) & bit == 0 {
+            return Some(asid);
+        }
+    }
+    None
+}
+
+/// Return an ASID from [`alloc_asid`]
+///
+/// Flush the ASID from all harts before it's reused, e.g. with [`shootdown_asid`].
+pub fn dealloc_asid(asid: usize) {
+    let (word, bit) = (asid / BITS_PER_WORD, 1 << (asid % BITS_PER_WORD));
+    ASID_BITMAP[word].fetch_and(!bit, Ordering::AcqRel);
+}
+
+/// Flush TLB entries for `va` of address space `asid` on current hart
+#[inline]
+pub fn flush_local(asid: usize, va: usize) {
+    unsafe { llvm_asm!("sfence.vma $0, $1" :: "r"(va), "r"(asid) : "memory" : "volatile") };
+}
+
+/// Flush all TLB entries on current hart
+#[inline]
+pub fn flush_local_all() {
+    unsafe { llvm_asm!("sfence.vma" ::: "memory" : "volatile") };
+}
+
+/// Flush TLB entries for `start..start + size` of address space `asid` on `harts`
+///
+/// Uses SBI RFENCE `remote_sfence_vma_asid`; current hart is flushed if it's in `harts`.
+#[inline]
+pub fn shootdown(harts: HartMask, asid: usize, start: usize, size: usize) -> Result<(), SbiError> {
+    sbi::remote_sfence_vma_asid(harts, start, size, asid)
+}
+
+/// Flush all TLB entries of address space `asid` on `harts`
+#[inline]
+pub fn shootdown_asid(harts: HartMask, asid: usize) -> Result<(), SbiError> {
+    sbi::remote_sfence_vma_asid(harts, 0, usize::MAX, asid)
+}
@@ -0,0 +1,40 @@
+//! Thread-local storage, following the RISC-V TLS ABI
+//!
+//! `#[thread_local]` statics are laid out by the linker script in `.tdata` (initial values)
+//! and `.tbss` (zeroed), and accessed at `tp` plus their offset in the TLS segment.
+//! Each hart gets a TLS block in its hart block, initialized before `main`.
+//!
+//! For contexts with a `tp` of their own, e.g. threads in user mode whose `tp` is restored
+//! from the [`TrapFrame`](crate::TrapFrame), allocate a block of [`layout`] and set `tp`
+//! to the value returned by [`init`]. Code in the kernel must keep `tp` of its hart.
+
+use core::alloc::Layout;
+
+// Alignment of TLS blocks, checked by the linker script
+const ALIGN: usize = 16;
+
+extern "C" {
+    static _stdata: u8;
+    static _etdata: u8;
+    static _tls_size: u8;
+}
+
+/// Layout of a TLS block
+#[inline]
+pub fn layout() -> Layout {
+    let size = unsafe { &_tls_size as *const u8 as usize };
+    Layout::from_size_align(size, ALIGN).unwrap()
+}
+
+/// Initialize the TLS block at `block`, and return the value of `tp` for it
+///
+/// # Safety
+///
+/// `block` must be valid for writes of [`layout`].
+pub unsafe fn init(block: *mut u8) -> usize {
+    let tdata = &_stdata as *const u8;
+    let tdata_size = &_etdata as *const u8 as usize - tdata as usize;
+    core::ptr::copy_nonoverlapping(tdata, block, tdata_size);
+    core::ptr::write_bytes(block.add(tdata_size), 0, layout().size() - tdata_size);
+    block as usize
+}
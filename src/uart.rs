@@ -0,0 +1,147 @@
+//! Driver of 16550-compatible UARTs, like `ns16550a` of QEMU virt
+//!
+//! Only the line settings are changed by [`Uart16550::init`]; the baud rate set by
+//! the firmware is kept.
+
+use crate::fdt::Node;
+
+// Registers, `1 << reg-shift` bytes apart
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const SCR: usize = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+// Enable and clear both FIFOs
+const FCR_FIFO: u8 = 0x07;
+// 8 data bits, no parity, 1 stop bit
+const LCR_8N1: u8 = 0x03;
+// DTR, RTS, and OUT2 which gates the interrupt line
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// A 16550-compatible UART
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Uart16550 {
+    base: usize,
+    shift: usize,
+    width: usize,
+}
+
+impl Uart16550 {
+    /// UART at `base`, with registers `1 << shift` bytes apart and accessed `width` bytes
+    /// at a time, 1 or 4
+    #[inline]
+    pub const fn new(base: usize, shift: usize, width: usize) -> Self {
+        Uart16550 { base, shift, width }
+    }
+
+    /// UART of a device tree node compatible with `ns16550a`, `ns16550` or `ns16450`
+    ///
+    /// Uses the address in the device tree; with paging, move it with [`with_base`].
+    ///
+    /// [`with_base`]: Uart16550::with_base
+    pub fn from_node(node: &Node) -> Option<Self> {
+        if !["ns16550a", "ns16550", "ns16450"]
+            .iter()
+            .any(|c| node.is_compatible(c))
+        {
+            return None;
+        }
+        let (base, _) = node.reg().next()?;
+        let shift = node.property_u32("reg-shift").unwrap_or(0) as usize;
+        let width = node.property_u32("reg-io-width").unwrap_or(1) as usize;
+        Some(Uart16550::new(base as usize, shift, width))
+    }
+
+    /// The same UART at `base`, e.g. a virtual address it's mapped to
+    #[inline]
+    pub const fn with_base(self, base: usize) -> Self {
+        Uart16550 { base, ..self }
+    }
+
+    /// Base address of registers
+    #[inline]
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Registers are `1 << shift` bytes apart
+    #[inline]
+    pub fn shift(&self) -> usize {
+        self.shift
+    }
+
+    /// Bytes accessed at a time, 1 or 4
+    #[inline]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Set 8N1 with FIFOs and disable interrupts; returns false if no UART responds
+    pub fn init(&self) -> bool {
+        // The scratch register reads back what's written on a real UART
+        self.write(SCR, 0x5a);
+        if self.read(SCR) != 0x5a {
+            return false;
+        }
+        self.write(IER, 0);
+        self.write(FCR, FCR_FIFO);
+        self.write(LCR, LCR_8N1);
+        self.write(MCR, MCR_DTR_RTS_OUT2);
+        true
+    }
+
+    /// Write a byte, waiting for space in the transmitter
+    pub fn putchar(&self, byte: u8) {
+        while self.read(LSR) & LSR_THR_EMPTY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        self.write(RBR_THR, byte);
+    }
+
+    /// Read a received byte, if any
+    pub fn getchar(&self) -> Option<u8> {
+        if self.read(LSR) & LSR_DATA_READY != 0 {
+            Some(self.read(RBR_THR))
+        } else {
+            None
+        }
+    }
+
+    /// Raise the interrupt line while received data is available
+    #[inline]
+    pub fn enable_rx_interrupt(&self) {
+        self.write(IER, IER_RX_AVAILABLE);
+    }
+
+    /// Stop raising the interrupt line
+    #[inline]
+    pub fn disable_rx_interrupt(&self) {
+        self.write(IER, 0);
+    }
+
+    fn read(&self, register: usize) -> u8 {
+        let address = self.base + (register << self.shift);
+        unsafe {
+            match self.width {
+                4 => (address as *const u32).read_volatile() as u8,
+                _ => (address as *const u8).read_volatile(),
+            }
+        }
+    }
+
+    fn write(&self, register: usize, value: u8) {
+        let address = self.base + (register << self.shift);
+        unsafe {
+            match self.width {
+                4 => (address as *mut u32).write_volatile(u32::from(value)),
+                _ => (address as *mut u8).write_volatile(value),
+            }
+        }
+    }
+}